@@ -0,0 +1,72 @@
+use crate::base64;
+
+// The `--auth` credentials, kept as the `user:password` string a client's
+// Basic credentials decode to.
+#[derive(Debug, Clone)]
+pub struct Credentials(String);
+
+impl Credentials {
+    // None unless `value` has the `user:password` shape.
+    pub fn parse(value: &str) -> Option<Credentials> {
+        value.split_once(':')?;
+        Some(Credentials(value.to_string()))
+    }
+
+    // Whether an Authorization header value carries these credentials.
+    pub fn check(&self, authorization: &str) -> bool {
+        let Some((scheme, encoded)) = authorization.trim().split_once(' ') else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return false;
+        }
+        match base64::decode(encoded.trim()) {
+            Some(decoded) => constant_time_eq(&decoded, self.0.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+// Compares every byte whatever the first mismatch, so the time taken doesn't
+// tell a guesser how much of the password they got right. Only the length
+// leaks.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{exchange, replies, request, temp_dir};
+
+    #[test]
+    fn check_wants_the_exact_basic_credentials() {
+        let credentials = Credentials::parse("user:pass").unwrap();
+        // "user:pass", "user:wrong"
+        assert!(credentials.check("Basic dXNlcjpwYXNz"));
+        assert!(credentials.check("basic  dXNlcjpwYXNz "));
+        assert!(!credentials.check("Basic dXNlcjp3cm9uZw=="));
+        assert!(!credentials.check("Bearer dXNlcjpwYXNz"));
+        assert!(!credentials.check("Basic not base64!"));
+        assert!(!credentials.check(""));
+        assert!(Credentials::parse("no-colon").is_none());
+    }
+
+    #[test]
+    fn files_need_the_right_credentials() {
+        let dir = temp_dir("auth");
+        std::fs::write(dir.join("a.txt"), "secret").unwrap();
+        let args = ["--directory", dir.to_str().unwrap(), "--auth", "user:pass"];
+        let get = |headers: &[(&str, &str)]| replies(&exchange(&args, &request("GET", "/files/a.txt", headers, b""))).remove(0);
+
+        let missing = get(&[]);
+        assert_eq!(missing.status, 401);
+        assert_eq!(missing.header("WWW-Authenticate").map(|value| value.starts_with("Basic realm=")), Some(true));
+        assert_eq!(get(&[("Authorization", "Basic dXNlcjp3cm9uZw==")]).status, 401);
+        let correct = get(&[("Authorization", "Basic dXNlcjpwYXNz")]);
+        assert_eq!(correct.status, 200);
+        assert_eq!(correct.body, b"secret");
+        // routes outside /files/ stay open without --auth-all
+        assert_eq!(replies(&exchange(&args, &request("GET", "/echo/hi", &[], b"")))[0].status, 200);
+    }
+}
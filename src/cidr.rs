@@ -0,0 +1,55 @@
+use std::{net::IpAddr, str::FromStr};
+
+// An address block like `10.0.0.0/8` or `2001:db8::/32`. A bare address is a
+// block of one.
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Cidr, ()> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| ())?;
+        let width = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| ())?,
+            None => width,
+        };
+        if prefix > width {
+            return Err(());
+        }
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual-stack socket show up as ::ffff:a.b.c.d
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Denies win; with an allow list, only addresses on it get in.
+pub fn permitted(ip: IpAddr, allow: &[Cidr], deny: &[Cidr]) -> bool {
+    if deny.iter().any(|cidr| cidr.contains(ip)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|cidr| cidr.contains(ip))
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+use crate::{config::Config, negotiate::quality_list, response::Response};
+
+// Media types compressed unless `--compressible-types` says otherwise. Images,
+// video and archives are already compressed, so another pass only costs CPU.
+pub const COMPRESSIBLE_TYPES: &[&str] = &[
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "image/svg+xml",
+];
+
+// Brotli's top quality is far too slow to spend on every response.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_BITS: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+// A content coding the server can compress responses with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    // The Content-Encoding token.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+// How responses may be compressed, as set by `--compression`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionMode {
+    Off,
+    // this codec only, for clients that accept it
+    Only(Encoding),
+    // whichever the client prefers, ties going to br, then gzip, then deflate
+    Auto,
+}
+
+impl FromStr for CompressionMode {
+    type Err = ();
+
+    fn from_str(mode: &str) -> Result<CompressionMode, ()> {
+        match mode {
+            "off" => Ok(CompressionMode::Off),
+            "br" => Ok(CompressionMode::Only(Encoding::Brotli)),
+            "gzip" => Ok(CompressionMode::Only(Encoding::Gzip)),
+            "deflate" => Ok(CompressionMode::Only(Encoding::Deflate)),
+            "auto" => Ok(CompressionMode::Auto),
+            _ => Err(()),
+        }
+    }
+}
+
+// How much the client's Accept-Encoding wants `coding`: its own q-value, or
+// else that of `*`, and 0 when it mentions neither.
+fn quality(headers: &HashMap<String,String>, coding: &str) -> f32 {
+    let Some(accept_encoding) = headers.get("accept-encoding") else {
+        return 0.0;
+    };
+    let codings = quality_list(accept_encoding);
+    let find = |wanted: &str| codings.iter().find(|(coding, _)| coding == wanted).map(|(_, quality)| *quality);
+    find(coding).or_else(|| find("*")).unwrap_or(0.0)
+}
+
+pub fn accepts_gzip(headers: &HashMap<String,String>) -> bool {
+    quality(headers, "gzip") > 0.0
+}
+
+// The codec a response to a request with `headers` should be compressed
+// with, or None if it should go out as it is.
+pub fn choose(headers: &HashMap<String,String>, mode: CompressionMode) -> Option<Encoding> {
+    let candidates: &[Encoding] = match &mode {
+        CompressionMode::Off => &[],
+        CompressionMode::Only(encoding) => std::slice::from_ref(encoding),
+        CompressionMode::Auto => &[Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in candidates {
+        let quality = quality(headers, encoding.as_str());
+        if quality > 0.0 && !best.is_some_and(|(_, best)| quality <= best) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+// Whether a body `length` bytes long of `content_type` is worth compressing.
+pub fn compressible(config: &Config, content_type: Option<&str>, length: u64) -> bool {
+    let Some(content_type) = content_type else {
+        return false;
+    };
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let kind = media_type.split('/').next().unwrap_or("");
+    length >= config.compression_min_size
+        && config.compressible_types.iter().any(|compressible| {
+            compressible == &media_type || compressible.strip_suffix("/*") == Some(kind)
+        })
+}
+
+fn compress(body: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut encoder =
+                brotli::CompressorWriter::new(Vec::new(), BROTLI_BUFFER_SIZE, BROTLI_QUALITY, BROTLI_WINDOW_BITS);
+            encoder.write_all(body)?;
+            encoder.flush()?;
+            Ok(encoder.into_inner())
+        }
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            // HTTP's "deflate" means zlib-wrapped, not a raw deflate stream
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+    }
+}
+
+// Wraps `reader` so it reads out compressed, for bodies too big to compress
+// in memory.
+pub fn encoder<R: Read + Send + 'static>(reader: R, encoding: Encoding) -> Box<dyn Read + Send> {
+    match encoding {
+        Encoding::Brotli => Box::new(brotli::CompressorReader::new(
+            reader,
+            BROTLI_BUFFER_SIZE,
+            BROTLI_QUALITY,
+            BROTLI_WINDOW_BITS,
+        )),
+        Encoding::Gzip => Box::new(flate2::read::GzEncoder::new(reader, Compression::default())),
+        Encoding::Deflate => Box::new(flate2::read::ZlibEncoder::new(reader, Compression::default())),
+    }
+}
+
+// Attaches `body` to the response, compressed with whichever codec
+// `--compression` and the client's Accept-Encoding settle on, provided it's
+// big enough and of a type worth compressing.
+pub fn with_body(response: Response, body: &[u8], headers: &HashMap<String,String>, config: &Config) -> Response {
+    if config.compression == CompressionMode::Off
+        || !compressible(config, response.header_value("Content-Type"), body.len() as u64)
+    {
+        return response.body(body);
+    }
+    // the body now depends on Accept-Encoding, even when this client gets
+    // it uncompressed
+    let response = response.vary("Accept-Encoding");
+    let Some(encoding) = choose(headers, config.compression) else {
+        return response.body(body);
+    };
+    match compress(body, encoding) {
+        Ok(compressed) => response.header("Content-Encoding", encoding.as_str()).body(compressed),
+        Err(e) => {
+            eprintln!("Failed to {} response body: {}", encoding.as_str(), e);
+            response.body(body)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, exchange, replies, request, temp_dir};
+
+    #[test]
+    fn compressible_wants_a_big_enough_body_of_a_listed_type() {
+        let config = config(&[]);
+        assert!(compressible(&config, Some("text/html; charset=utf-8"), 4096));
+        assert!(compressible(&config, Some("application/json"), 4096));
+        assert!(!compressible(&config, Some("text/html"), 100));
+        assert!(!compressible(&config, Some("image/png"), 4096));
+        assert!(!compressible(&config, None, 4096));
+    }
+
+    #[test]
+    fn small_and_image_files_go_out_uncompressed() {
+        let dir = temp_dir("compression");
+        std::fs::write(dir.join("big.txt"), "text ".repeat(1000)).unwrap();
+        std::fs::write(dir.join("small.txt"), "tiny").unwrap();
+        // compresses well, but isn't worth another pass
+        std::fs::write(dir.join("big.png"), [0u8; 5000]).unwrap();
+        let args = ["--directory", dir.to_str().unwrap()];
+        let get = |name| {
+            let target = format!("/files/{}", name);
+            replies(&exchange(&args, &request("GET", &target, &[("Accept-Encoding", "gzip")], b""))).remove(0)
+        };
+        assert_eq!(get("big.txt").header("Content-Encoding"), Some("gzip"));
+        let small = get("small.txt");
+        assert_eq!(small.header("Content-Encoding"), None);
+        assert_eq!(small.body, b"tiny");
+        let image = get("big.png");
+        assert_eq!(image.header("Content-Encoding"), None);
+        assert_eq!(image.body.len(), 5000);
+    }
+}
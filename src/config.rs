@@ -0,0 +1,433 @@
+use std::{collections::HashMap, env, path::PathBuf, thread, time::Duration};
+use thiserror::Error;
+
+use crate::{auth::Credentials, cidr::Cidr, compression::{CompressionMode, COMPRESSIBLE_TYPES}, log::{LogFormat, LogLevel}, proxy::Upstream};
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 4221;
+pub const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+const DEFAULT_MAX_REQUEST_LINE_BYTES: usize = 8 * 1024;
+const DEFAULT_MAX_HEADER_BYTES: usize = 16 * 1024;
+const DEFAULT_MAX_HEADERS: usize = 100;
+const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
+const DEFAULT_KEEP_ALIVE_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_READ_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_WRITE_TIMEOUT_MS: u64 = 30000;
+const DEFAULT_BACKLOG: i32 = 1024;
+const DEFAULT_COMPRESSION_MIN_SIZE: u64 = 1024;
+const DEFAULT_INDEX: &str = "index.html";
+const DEFAULT_SERVER_NAME: &str = concat!("rust-http/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("missing value for {0}")]
+    MissingValue(String),
+    #[error("invalid value {value:?} for {flag}")]
+    InvalidValue { flag: String, value: String },
+    #[error("unknown argument {0}")]
+    UnknownArgument(String),
+    #[error("{flag} needs {needs} as well")]
+    MissingCompanion { flag: String, needs: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub threads: usize,
+    // where /files/ reads and writes; those routes are disabled without it
+    pub directory: Option<PathBuf>,
+    // bytes; requests declaring a larger body get 413
+    pub max_body_size: usize,
+    // bytes, method and version included; longer request lines get 414
+    pub max_request_line_bytes: usize,
+    // bytes, request line included; larger header sections get 431
+    pub max_header_bytes: usize,
+    // header lines; requests with more get 431
+    pub max_headers: usize,
+    // product string sent in the Server header
+    pub server_name: String,
+    // list dotfiles in /files/ directory listings
+    pub show_hidden: bool,
+    // file served in place of a directory listing when a directory has one
+    pub index: String,
+    // where the access log is appended; stdout when unset
+    pub access_log: Option<PathBuf>,
+    pub log_level: LogLevel,
+    // how each access log entry is written
+    pub log_format: LogFormat,
+    // value of Access-Control-Allow-Origin; CORS headers are only sent when set
+    pub cors_origin: Option<String>,
+    // serve Prometheus counters on /metrics
+    pub enable_metrics: bool,
+    // keep-alive connections are closed after this many requests; 0 for no limit
+    pub max_requests_per_connection: usize,
+    // how long a keep-alive connection may sit between requests
+    pub keep_alive_timeout: Duration,
+    // how long shutdown waits for open connections before exiting anyway;
+    // None waits as long as they take
+    pub shutdown_timeout: Option<Duration>,
+    // longest wait for the next bytes of a request before giving up on it
+    pub read_timeout: Duration,
+    // longest a client may go without taking any of a response before it's
+    // abandoned and the connection closed
+    pub write_timeout: Duration,
+    // PEM certificate chain and private key; HTTPS is served when both are set
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // Unix socket to listen on instead of host and port
+    pub unix: Option<PathBuf>,
+    // Basic auth credentials required for /files/, or every route with auth_all
+    pub auth: Option<Credentials>,
+    pub auth_all: bool,
+    // requests a second each client IP may make before getting 429
+    pub rate_limit: Option<u32>,
+    // address blocks connections are accepted from; any when empty
+    pub allow: Vec<Cidr>,
+    // address blocks whose connections are dropped, even if allowed
+    pub deny: Vec<Cidr>,
+    // send small responses right away rather than waiting to coalesce them
+    pub no_delay: bool,
+    // SO_REUSEADDR on the listening socket, so restarts don't wait out TIME_WAIT
+    pub reuse_addr: bool,
+    // connections the kernel queues for a TCP listener before accept() takes
+    // them; it may cap this lower (somaxconn on Linux)
+    pub backlog: i32,
+    // HTML files sent as the body of responses with these error statuses
+    pub error_pages: HashMap<u16, PathBuf>,
+    // HTML file sent with the 404 for a path no route matches
+    pub not_found_file: Option<PathBuf>,
+    // serve the root index for GETs of missing /files/ paths without an extension
+    pub spa: bool,
+    // skip the startup summary
+    pub quiet: bool,
+    // path prefixes (without a trailing slash) forwarded to another server
+    pub proxies: Vec<(String, Upstream)>,
+    // seconds clients may cache /files/ responses for; no caching headers when unset
+    pub cache_max_age: Option<u64>,
+    // send `name.gz` from beside a /files/ file to clients that accept gzip
+    pub precompressed: bool,
+    // which codecs responses may be compressed with
+    pub compression: CompressionMode,
+    // smaller bodies go out uncompressed, since there's little to gain
+    pub compression_min_size: u64,
+    // media types worth compressing; `type/*` covers a whole type
+    pub compressible_types: Vec<String>,
+    // connections served or queued at once; more wait in the listen backlog. 0 for no limit
+    pub max_connections: usize,
+    // print the routes this configuration would serve and exit
+    pub list_routes: bool,
+    // plain HTTP port whose requests are all redirected to HTTPS
+    pub redirect_http: Option<u16>,
+    // (lowercased host name, document root) pairs; /files/ requests for a
+    // host not listed get `directory`
+    pub vhosts: Vec<(String, PathBuf)>,
+}
+
+impl Config {
+    // Flags win over the matching environment variables, which win over the defaults.
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Config, ConfigError> {
+        let mut host = None;
+        let mut port = None;
+        let mut threads = None;
+        let mut directory = None;
+        let mut max_body_size = MAX_BODY_SIZE;
+        let mut max_request_line_bytes = DEFAULT_MAX_REQUEST_LINE_BYTES;
+        let mut max_header_bytes = DEFAULT_MAX_HEADER_BYTES;
+        let mut max_headers = DEFAULT_MAX_HEADERS;
+        let mut server_name = DEFAULT_SERVER_NAME.to_string();
+        let mut show_hidden = false;
+        let mut index = DEFAULT_INDEX.to_string();
+        let mut access_log = None;
+        let mut log_level = LogLevel::Info;
+        let mut log_format = LogFormat::Text;
+        let mut cors_origin = None;
+        let mut enable_metrics = false;
+        let mut max_requests_per_connection = DEFAULT_MAX_REQUESTS_PER_CONNECTION;
+        let mut keep_alive_timeout = DEFAULT_KEEP_ALIVE_TIMEOUT_SECS;
+        let mut shutdown_timeout = None;
+        let mut read_timeout = DEFAULT_READ_TIMEOUT_MS;
+        let mut write_timeout = DEFAULT_WRITE_TIMEOUT_MS;
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        let mut unix = None;
+        let mut auth = None;
+        let mut auth_all = false;
+        let mut rate_limit = None;
+        let mut allow = Vec::new();
+        let mut deny = Vec::new();
+        let mut no_delay = true;
+        let mut reuse_addr = true;
+        let mut backlog = DEFAULT_BACKLOG;
+        let mut error_pages = HashMap::new();
+        let mut not_found_file = None;
+        let mut spa = false;
+        let mut quiet = false;
+        let mut proxies = Vec::new();
+        let mut cache_max_age = None;
+        let mut precompressed = false;
+        let mut compression = CompressionMode::Auto;
+        let mut compression_min_size = DEFAULT_COMPRESSION_MIN_SIZE;
+        let mut compressible_types: Vec<String> = COMPRESSIBLE_TYPES.iter().map(|t| t.to_string()).collect();
+        let mut max_connections = 0;
+        let mut list_routes = false;
+        let mut redirect_http = None;
+        let mut vhosts = Vec::new();
+
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--host" => host = Some(value_for(&flag, &mut args)?),
+                "--port" => port = Some(value_for(&flag, &mut args)?),
+                "--threads" => threads = Some(value_for(&flag, &mut args)?),
+                "--max-body-size" => max_body_size = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--keep-alive-timeout" => keep_alive_timeout = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--shutdown-timeout" => {
+                    shutdown_timeout = Some(Duration::from_secs(parse_value(&flag, &value_for(&flag, &mut args)?)?))
+                }
+                "--max-connections" => max_connections = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--max-requests-per-connection" => {
+                    max_requests_per_connection = parse_value(&flag, &value_for(&flag, &mut args)?)?
+                }
+                "--read-timeout" => read_timeout = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--write-timeout" => write_timeout = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--max-request-line-bytes" => {
+                    max_request_line_bytes = parse_value(&flag, &value_for(&flag, &mut args)?)?
+                }
+                "--max-header-bytes" => max_header_bytes = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--max-headers" => max_headers = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--server-name" => server_name = value_for(&flag, &mut args)?,
+                "--show-hidden" => show_hidden = true,
+                "--list-routes" => list_routes = true,
+                "--index" => index = value_for(&flag, &mut args)?,
+                "--access-log" => access_log = Some(PathBuf::from(value_for(&flag, &mut args)?)),
+                "--log-level" => log_level = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--log-format" => log_format = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--cors-origin" => cors_origin = Some(value_for(&flag, &mut args)?),
+                "--enable-metrics" => enable_metrics = true,
+                "--tls-cert" => tls_cert = Some(PathBuf::from(value_for(&flag, &mut args)?)),
+                "--tls-key" => tls_key = Some(PathBuf::from(value_for(&flag, &mut args)?)),
+                "--vhost" => {
+                    let value = value_for(&flag, &mut args)?;
+                    match value.split_once('=') {
+                        Some((host, root)) if !host.is_empty() && !root.is_empty() => {
+                            vhosts.push((host.to_ascii_lowercase(), PathBuf::from(root)))
+                        }
+                        _ => return Err(ConfigError::InvalidValue { flag, value }),
+                    }
+                }
+                "--redirect-http" => redirect_http = Some(parse_value(&flag, &value_for(&flag, &mut args)?)?),
+                "--unix" => unix = Some(PathBuf::from(value_for(&flag, &mut args)?)),
+                "--auth" => {
+                    let value = value_for(&flag, &mut args)?;
+                    match Credentials::parse(&value) {
+                        Some(credentials) => auth = Some(credentials),
+                        None => return Err(ConfigError::InvalidValue { flag, value }),
+                    }
+                }
+                "--auth-all" => auth_all = true,
+                "--rate-limit" => rate_limit = Some(parse_value(&flag, &value_for(&flag, &mut args)?)?),
+                "--allow" => allow.push(parse_value(&flag, &value_for(&flag, &mut args)?)?),
+                "--deny" => deny.push(parse_value(&flag, &value_for(&flag, &mut args)?)?),
+                "--no-delay" => no_delay = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--reuse-addr" => reuse_addr = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--backlog" => backlog = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--error-page" => {
+                    let value = value_for(&flag, &mut args)?;
+                    let page = value.split_once('=').and_then(|(status, page)| match status.parse::<u16>() {
+                        Ok(status @ 400..=599) => Some((status, PathBuf::from(page))),
+                        _ => None,
+                    });
+                    match page {
+                        Some((status, page)) => error_pages.insert(status, page),
+                        None => return Err(ConfigError::InvalidValue { flag, value }),
+                    };
+                }
+                "--not-found-file" => not_found_file = Some(PathBuf::from(value_for(&flag, &mut args)?)),
+                "--spa" => spa = true,
+                "--quiet" => quiet = true,
+                "--proxy" => {
+                    let value = value_for(&flag, &mut args)?;
+                    let proxy = value.split_once('=').and_then(|(prefix, url)| {
+                        let prefix = prefix.trim_end_matches('/');
+                        match (prefix.starts_with('/'), url.parse::<Upstream>()) {
+                            (true, Ok(upstream)) => Some((prefix.to_string(), upstream)),
+                            _ => None,
+                        }
+                    });
+                    match proxy {
+                        Some(proxy) => proxies.push(proxy),
+                        None => return Err(ConfigError::InvalidValue { flag, value }),
+                    }
+                }
+                "--cache-max-age" => cache_max_age = Some(parse_value(&flag, &value_for(&flag, &mut args)?)?),
+                "--precompressed" => precompressed = true,
+                "--compression" => compression = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--compression-min-size" => compression_min_size = parse_value(&flag, &value_for(&flag, &mut args)?)?,
+                "--compressible-types" => {
+                    compressible_types = value_for(&flag, &mut args)?
+                        .split(',')
+                        .map(|media_type| media_type.trim().to_ascii_lowercase())
+                        .filter(|media_type| !media_type.is_empty())
+                        .collect()
+                }
+                "--directory" => directory = Some(PathBuf::from(value_for(&flag, &mut args)?)),
+                _ => return Err(ConfigError::UnknownArgument(flag)),
+            }
+        }
+
+        let host = host
+            .or_else(|| env::var("HOST").ok())
+            .unwrap_or_else(|| DEFAULT_HOST.to_string());
+        let (host, host_port) = split_host(&host)?;
+        // an explicit --port wins over one written into the host
+        let port = match port.or_else(|| env::var("PORT").ok()) {
+            Some(port) => parse_value("--port", &port)?,
+            None => host_port.unwrap_or(DEFAULT_PORT),
+        };
+
+        let threads = match threads.or_else(|| env::var("THREADS").ok()) {
+            Some(threads) => match parse_value("--threads", &threads)? {
+                0 => {
+                    return Err(ConfigError::InvalidValue {
+                        flag: "--threads".to_string(),
+                        value: threads,
+                    })
+                }
+                n => n,
+            },
+            None => thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+
+        // sockets reject a zero timeout
+        for (flag, timeout) in [("--read-timeout", read_timeout), ("--write-timeout", write_timeout)] {
+            if timeout == 0 {
+                return Err(ConfigError::InvalidValue { flag: flag.to_string(), value: timeout.to_string() });
+            }
+        }
+
+        if backlog <= 0 {
+            return Err(ConfigError::InvalidValue {
+                flag: "--backlog".to_string(),
+                value: backlog.to_string(),
+            });
+        }
+
+        match (&tls_cert, &tls_key) {
+            (Some(_), None) => {
+                return Err(ConfigError::MissingCompanion {
+                    flag: "--tls-cert".to_string(),
+                    needs: "--tls-key".to_string(),
+                })
+            }
+            (None, Some(_)) => {
+                return Err(ConfigError::MissingCompanion {
+                    flag: "--tls-key".to_string(),
+                    needs: "--tls-cert".to_string(),
+                })
+            }
+            _ => {}
+        }
+        if rate_limit == Some(0) {
+            return Err(ConfigError::InvalidValue {
+                flag: "--rate-limit".to_string(),
+                value: "0".to_string(),
+            });
+        }
+        if redirect_http.is_some() && tls_cert.is_none() {
+            return Err(ConfigError::MissingCompanion {
+                flag: "--redirect-http".to_string(),
+                needs: "--tls-cert".to_string(),
+            });
+        }
+        if auth_all && auth.is_none() {
+            return Err(ConfigError::MissingCompanion {
+                flag: "--auth-all".to_string(),
+                needs: "--auth".to_string(),
+            });
+        }
+
+        Ok(Config {
+            host,
+            port,
+            threads,
+            directory,
+            max_body_size,
+            max_request_line_bytes,
+            max_header_bytes,
+            max_headers,
+            server_name,
+            show_hidden,
+            index,
+            access_log,
+            log_level,
+            log_format,
+            cors_origin,
+            enable_metrics,
+            max_requests_per_connection,
+            keep_alive_timeout: Duration::from_secs(keep_alive_timeout),
+            shutdown_timeout,
+            read_timeout: Duration::from_millis(read_timeout),
+            write_timeout: Duration::from_millis(write_timeout),
+            tls_cert,
+            tls_key,
+            unix,
+            auth,
+            auth_all,
+            rate_limit,
+            allow,
+            deny,
+            no_delay,
+            reuse_addr,
+            backlog,
+            error_pages,
+            not_found_file,
+            spa,
+            quiet,
+            proxies,
+            cache_max_age,
+            precompressed,
+            compression,
+            compression_min_size,
+            compressible_types,
+            max_connections,
+            list_routes,
+            redirect_http,
+            vhosts,
+        })
+    }
+}
+
+// Splits a port off `host`, so `127.0.0.1:8080`, `[::1]:8080` and `[::]`
+// all work. A bare IPv6 address like `::1` is left whole.
+fn split_host(host: &str) -> Result<(String, Option<u16>), ConfigError> {
+    let invalid = || ConfigError::InvalidValue {
+        flag: "--host".to_string(),
+        value: host.to_string(),
+    };
+    if let Some(bracketed) = host.strip_prefix('[') {
+        let (address, rest) = bracketed.split_once(']').ok_or_else(invalid)?;
+        return match rest.strip_prefix(':') {
+            Some(port) => Ok((address.to_string(), Some(port.parse().map_err(|_| invalid())?))),
+            None if rest.is_empty() => Ok((address.to_string(), None)),
+            None => Err(invalid()),
+        };
+    }
+    match host.split_once(':') {
+        // more than one colon means an unbracketed IPv6 address
+        Some((address, port)) if !port.contains(':') => {
+            Ok((address.to_string(), Some(port.parse().map_err(|_| invalid())?)))
+        }
+        _ => Ok((host.to_string(), None)),
+    }
+}
+
+fn value_for<I: Iterator<Item = String>>(flag: &str, args: &mut I) -> Result<String, ConfigError> {
+    args.next().ok_or_else(|| ConfigError::MissingValue(flag.to_string()))
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::InvalidValue {
+        flag: flag.to_string(),
+        value: value.to_string(),
+    })
+}
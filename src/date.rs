@@ -0,0 +1,157 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// The UTC calendar fields of a point in time.
+struct Civil {
+    weekday: usize,
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u64,
+    minute: u64,
+    second: u64,
+}
+
+fn civil(time: SystemTime) -> Civil {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = secs / 86_400;
+    let secs_of_day = secs % 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+    Civil {
+        // 1970-01-01 was a Thursday
+        weekday: ((days + 4) % 7) as usize,
+        year,
+        month,
+        day,
+        hour: secs_of_day / 3600,
+        minute: secs_of_day % 3600 / 60,
+        second: secs_of_day % 60,
+    }
+}
+
+// Formats `time` as an RFC 7231 IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    let t = civil(time);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[t.weekday],
+        t.day,
+        MONTHS[t.month as usize - 1],
+        t.year,
+        t.hour,
+        t.minute,
+        t.second
+    )
+}
+
+// Formats `time` the way Common Log Format access logs do, e.g.
+// `06/Nov/1994:08:49:37 +0000`.
+pub fn clf_date(time: SystemTime) -> String {
+    let t = civil(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        t.day,
+        MONTHS[t.month as usize - 1],
+        t.year,
+        t.hour,
+        t.minute,
+        t.second
+    )
+}
+
+// Formats `time` as RFC 3339 UTC to the millisecond, e.g.
+// `1994-11-06T08:49:37.000Z`, which log tooling sorts and parses readily.
+pub fn rfc3339(time: SystemTime) -> String {
+    let t = civil(time);
+    let millis = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_millis());
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        t.year, t.month, t.day, t.hour, t.minute, t.second, millis
+    )
+}
+
+// Parses an IMF-fixdate as produced by `http_date`. The obsolete RFC 850 and
+// asctime forms aren't understood and give None.
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let [_, day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| m == month)? as u32 + 1;
+    // IMF-fixdate years are four digits; anything else would overflow below
+    let year: i64 = year.parse().ok().filter(|year| (1..=9999).contains(year))?;
+
+    let mut clock = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some() || !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days.checked_mul(86_400)?.checked_add(hours * 3600 + minutes * 60 + seconds)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// (year, month, day) to days since the Unix epoch, after Howard Hinnant's
+// `days_from_civil`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Days since the Unix epoch to (year, month, day), after Howard Hinnant's
+// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{exchange, replies, request, temp_dir};
+
+    #[test]
+    fn parse_http_date_reads_what_http_date_writes() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+    }
+
+    #[test]
+    fn parse_http_date_refuses_years_out_of_range() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 922337203685477580 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 0 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov -1994 08:49:37 GMT"), None);
+        assert!(parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT").is_some());
+    }
+
+    #[test]
+    fn an_unreadable_if_modified_since_is_ignored() {
+        let dir = temp_dir("date");
+        std::fs::write(dir.join("a.txt"), "hello").unwrap();
+        let args = ["--directory", dir.to_str().unwrap()];
+        let headers = [("If-Modified-Since", "Sun, 06 Nov 922337203685477580 08:49:37 GMT")];
+        let reply = replies(&exchange(&args, &request("GET", "/files/a.txt", &headers, b""))).remove(0);
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body, b"hello");
+    }
+}
@@ -0,0 +1,587 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    compression::{self, accepts_gzip, with_body, CompressionMode},
+    config::Config,
+    date::{http_date, parse_http_date},
+    random,
+    request::{path_decode, Request},
+    response::Response,
+};
+
+// Files larger than this are compressed as they're sent rather than all at once in memory
+const MAX_BUFFERED_COMPRESSION_SIZE: u64 = 1024 * 1024;
+
+// Joins `name` onto `root` and resolves symlinks and `..`, returning None if
+// the result would end up outside `root`. The file itself may not exist yet
+// (uploads), in which case its parent directory is what gets checked.
+pub fn resolve_in_root(root: &Path, name: &str) -> Option<PathBuf> {
+    let root = root.canonicalize().ok()?;
+    let joined = root.join(name);
+    let resolved = match joined.canonicalize() {
+        Ok(path) => path,
+        Err(_) => joined.parent()?.canonicalize().ok()?.join(joined.file_name()?),
+    };
+    if resolved.starts_with(&root) {
+        Some(resolved)
+    } else {
+        eprintln!("Refusing path outside the served directory: {}", name);
+        None
+    }
+}
+
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "application/javascript"),
+    ("json", "application/json"),
+    ("txt", "text/plain"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("ico", "image/x-icon"),
+    ("webp", "image/webp"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("wasm", "application/wasm"),
+];
+
+pub fn content_type_for(path: &Path) -> &'static str {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| {
+            CONTENT_TYPES
+                .iter()
+                .find(|(known, _)| known.eq_ignore_ascii_case(ext))
+                .map(|(_, content_type)| *content_type)
+        })
+        .unwrap_or("application/octet-stream")
+}
+
+// The directory `/files/` serves for this request: the `--vhost` root for its
+// Host, matched without the port and ignoring case, else `--directory`.
+pub fn document_root<'a>(config: &'a Config, request: &Request) -> Option<&'a Path> {
+    let vhost = request.host().and_then(|host| {
+        config
+            .vhosts
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(host))
+            .map(|(_, root)| root.as_path())
+    });
+    vhost.or(config.directory.as_deref())
+}
+
+// Maps the `<name>` of a `/files/<name>` route onto the request's document
+// root. The name is percent-decoded first, so listing links to names with
+// spaces etc. resolve.
+pub fn file_path(config: &Config, request: &Request, name: &str) -> Option<PathBuf> {
+    let Some(directory) = document_root(config, request) else {
+        eprintln!("[{}] /files/{} requested but no --directory is configured", request.id, name);
+        return None;
+    };
+    resolve_in_root(directory, &path_decode(name))
+}
+
+// Creates `path` or replaces its contents with `contents`. The bytes go to a
+// temp file next to it that is then renamed into place, so readers only ever
+// see the old file or the complete new one, never a partial write.
+pub fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp_path = temp_path_for(path)?;
+    let written = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}
+
+// A dotfile in the same directory (rename can't cross filesystems), unique per
+// write so concurrent uploads of the same name don't share one.
+fn temp_path_for(path: &Path) -> io::Result<PathBuf> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    Ok(path.with_file_name(format!(".{}.{}.{}.tmp", name.to_string_lossy(), std::process::id(), id)))
+}
+
+// Most ranges one Range header may ask for before it's ignored.
+const MAX_RANGES: usize = 64;
+
+// What a `Range` header asks of a file `total` bytes long.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ByteRange {
+    // first and last byte positions, both inclusive, for each range asked
+    // for that the file can satisfy, in the order asked
+    Satisfiable(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+// Parses a `bytes=` header of one or more comma-separated ranges. Ranges
+// starting past the end of the file are dropped, and it's Unsatisfiable only
+// if that leaves none. Returns None for anything we don't handle or can't
+// make sense of (other units, garbage, more than MAX_RANGES ranges, or
+// several adding up to more than the whole file, as overlapping ones do), in
+// which case the header is ignored and the whole file is sent.
+pub fn parse_range(value: &str, total: u64) -> Option<ByteRange> {
+    let (unit, specs) = value.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let specs: Vec<&str> = specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()).collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs {
+        if let Some(range) = parse_range_spec(spec, total)? {
+            ranges.push(range);
+        }
+    }
+    if ranges.is_empty() {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    let requested: u64 = ranges.iter().map(|(start, end)| end - start + 1).sum();
+    if ranges.len() > 1 && requested > total {
+        return None;
+    }
+    Some(ByteRange::Satisfiable(ranges))
+}
+
+// One `first-last`, `first-` or `-suffix` range: None if it doesn't parse,
+// Some(None) if none of it lies within the file.
+fn parse_range_spec(spec: &str, total: u64) -> Option<Option<(u64, u64)>> {
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // `bytes=-N` is the last N bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || total == 0 {
+            return Some(None);
+        }
+        return Some(Some((total.saturating_sub(suffix), total - 1)));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end: Option<u64> = if end.is_empty() { None } else { Some(end.parse().ok()?) };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+    if start >= total {
+        return Some(None);
+    }
+    let end = end.map_or(total - 1, |end| end.min(total - 1));
+    Some(Some((start, end)))
+}
+
+// The body of a multipart/byteranges response: each range of `file` after a
+// boundary line and its own Content-Type and Content-Range. The ranges are
+// read from disk as it's sent, so asking for several costs no memory.
+struct ByteRanges {
+    file: File,
+    pieces: VecDeque<Piece>,
+}
+
+enum Piece {
+    Text(io::Cursor<Vec<u8>>),
+    // where the range is up to in the file, and how many bytes of it are left
+    Span(u64, u64),
+}
+
+impl ByteRanges {
+    // The body, and its length for Content-Length.
+    fn new(file: File, ranges: &[(u64, u64)], total: u64, content_type: &str, boundary: &str) -> (ByteRanges, u64) {
+        let mut pieces = VecDeque::new();
+        let mut length = 0;
+        let mut text = |pieces: &mut VecDeque<Piece>, text: String| {
+            length += text.len() as u64;
+            pieces.push_back(Piece::Text(io::Cursor::new(text.into_bytes())));
+        };
+        for &(start, end) in ranges {
+            let head = format!(
+                "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                boundary, content_type, start, end, total
+            );
+            text(&mut pieces, head);
+            pieces.push_back(Piece::Span(start, end - start + 1));
+            text(&mut pieces, "\r\n".to_string());
+        }
+        text(&mut pieces, format!("--{}--\r\n", boundary));
+        let spans: u64 = ranges.iter().map(|(start, end)| end - start + 1).sum();
+        (ByteRanges { file, pieces }, length + spans)
+    }
+}
+
+impl Read for ByteRanges {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while let Some(piece) = self.pieces.front_mut() {
+            let n = match piece {
+                Piece::Text(text) => text.read(buf)?,
+                Piece::Span(_, 0) => 0,
+                Piece::Span(position, remaining) => {
+                    self.file.seek(SeekFrom::Start(*position))?;
+                    let wanted = (*remaining).min(buf.len() as u64) as usize;
+                    let n = self.file.read(&mut buf[..wanted])?;
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated while sending"));
+                    }
+                    *position += n as u64;
+                    *remaining -= n as u64;
+                    n
+                }
+            };
+            if n > 0 {
+                return Ok(n);
+            }
+            self.pieces.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+// Separates the parts of a multipart/byteranges body. It's random so that it
+// won't turn up in the file itself.
+fn byteranges_boundary() -> String {
+    random::hex_token()
+}
+
+// Answers a GET (or HEAD) for a path already resolved inside the served
+// directory. Files honour Range and the If-None-Match / If-Modified-Since
+// validators; directories get a listing.
+pub fn serve_file(file_path: &Path, request: &Request, config: &Config) -> io::Result<Response> {
+    let headers = &request.headers;
+    let (metadata, mut file) = match File::open(file_path).and_then(|file| Ok((file.metadata()?, file))) {
+        Ok((metadata, _)) if metadata.is_dir() => return serve_directory(file_path, request, config),
+        Ok((metadata, _)) if !metadata.is_file() => return Ok(Response::not_found()),
+        Ok(opened) => opened,
+        Err(_) => return Ok(Response::not_found()),
+    };
+    let total = metadata.len();
+    let modified = metadata.modified().ok();
+    let last_modified = modified.map(http_date);
+    let etag = weak_etag(total, modified);
+
+    // 304, 200 and 206 all carry the validators the client should cache, and
+    // how long it may cache for
+    let with_validators = |response: Response| {
+        let mut response = response.header("ETag", &etag);
+        if let Some(last_modified) = &last_modified {
+            response = response.header("Last-Modified", last_modified);
+        }
+        if let Some(max_age) = config.cache_max_age {
+            let expires = SystemTime::now() + Duration::from_secs(max_age);
+            response = response
+                .header("Cache-Control", &format!("public, max-age={}", max_age))
+                .header("Expires", &http_date(expires));
+        }
+        response
+    };
+
+    // If-None-Match wins when both are sent; If-Modified-Since is then ignored
+    let not_modified = match headers.get("if-none-match") {
+        Some(candidates) => etag_matches(candidates, &etag),
+        None => match (modified, headers.get("if-modified-since").and_then(|v| parse_http_date(v))) {
+            // HTTP dates only carry whole seconds, so compare at that resolution
+            (Some(modified), Some(since)) => unix_secs(since) >= unix_secs(modified),
+            _ => false,
+        },
+    };
+    if not_modified {
+        return Ok(with_validators(Response::not_modified()));
+    }
+
+    let describe = |response: Response| with_validators(response.header("Content-Type", content_type_for(file_path)));
+    let response = describe(Response::ok()).header("Accept-Ranges", "bytes");
+
+    if let Some(range) = headers.get("range") {
+        return Ok(match parse_range(range, total) {
+            Some(ByteRange::Satisfiable(ranges)) if ranges.len() == 1 => {
+                let (start, end) = ranges[0];
+                file.seek(SeekFrom::Start(start))?;
+                describe(Response::partial_content())
+                    .header("Content-Range", &format!("bytes {}-{}/{}", start, end, total))
+                    .file(file, end - start + 1)
+            }
+            Some(ByteRange::Satisfiable(ranges)) => {
+                let boundary = byteranges_boundary();
+                let (body, length) = ByteRanges::new(file, &ranges, total, content_type_for(file_path), &boundary);
+                with_validators(Response::partial_content())
+                    .header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary))
+                    .reader(body, length)
+            }
+            Some(ByteRange::Unsatisfiable) => Response::range_not_satisfiable()
+                .header("Content-Range", &format!("bytes */{}", total)),
+            None => response.file(file, total),
+        });
+    }
+
+    if config.precompressed && accepts_gzip(headers) {
+        if let Some((sidecar, length)) = open_sidecar(file_path) {
+            return Ok(response
+                .vary("Accept-Encoding")
+                .header("Content-Encoding", "gzip")
+                .file(sidecar, length));
+        }
+    }
+    let compressible = config.compression != CompressionMode::Off
+        && compression::compressible(config, Some(content_type_for(file_path)), total);
+    let encoding = compression::choose(headers, config.compression).filter(|_| compressible);
+    if let Some(encoding) = encoding {
+        if total <= MAX_BUFFERED_COMPRESSION_SIZE {
+            return Ok(match std::fs::read(file_path) {
+                Ok(content) => with_body(response, &content, headers, config),
+                Err(_) => Response::not_found(),
+            });
+        }
+        // the compressed length isn't known until the end, so it goes out
+        // chunked, which HTTP/1.0 clients can't read
+        if request.version == "HTTP/1.1" {
+            return Ok(response
+                .vary("Accept-Encoding")
+                .header("Content-Encoding", encoding.as_str())
+                .stream(compression::encoder(file, encoding)));
+        }
+    }
+    // another client might have got it compressed
+    let response = if compressible { response.vary("Accept-Encoding") } else { response };
+    Ok(response.file(file, total))
+}
+
+// `name.gz` next to `name`, compressed ahead of time, if there is one.
+fn open_sidecar(file_path: &Path) -> Option<(File, u64)> {
+    let mut sidecar = file_path.as_os_str().to_owned();
+    sidecar.push(".gz");
+    let file = File::open(sidecar).ok()?;
+    let metadata = file.metadata().ok().filter(|metadata| metadata.is_file())?;
+    Some((file, metadata.len()))
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// A directory's index file is served if it has one, otherwise its entries are
+// listed. Links in either are relative, so the directory itself has to be
+// addressed with a trailing slash for them to resolve under it.
+fn serve_directory(dir: &Path, request: &Request, config: &Config) -> io::Result<Response> {
+    let route = &request.path;
+    if !route.ends_with('/') {
+        return Ok(Response::moved_permanently(&format!("{}/", route)));
+    }
+    let index = dir.join(&config.index);
+    if index.is_file() {
+        return serve_file(&index, request, config);
+    }
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("[{}] Failed to list {}: {}", request.id, dir.display(), e);
+            return Ok(Response::not_found());
+        }
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+            Some(if is_dir { format!("{}/", name) } else { name })
+        })
+        .filter(|name| config.show_hidden || !name.starts_with('.'))
+        .collect();
+    names.sort();
+
+    let title = html_escape(&path_decode(route));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+        title
+    );
+    if route != "/files/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for name in &names {
+        html.push_str(&format!(
+            "<li><a href=\"{}\">{}</a></li>\n",
+            percent_encode(name),
+            html_escape(name)
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+
+    Ok(Response::ok().header("Content-Type", "text/html; charset=utf-8").body(html))
+}
+
+// Escapes everything but unreserved characters and `/`, so a file name can be
+// used as a relative link.
+fn percent_encode(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Size and mtime change whenever the file is rewritten, which is enough for a
+// weak validator without hashing the contents.
+fn weak_etag(size: u64, modified: Option<SystemTime>) -> String {
+    let modified = modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("W/\"{:x}-{:x}.{:x}\"", size, modified.as_secs(), modified.subsec_nanos())
+}
+
+// If-None-Match is `*` or a list of entity tags, compared weakly (the `W/`
+// prefix is ignored on both sides).
+fn etag_matches(candidates: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    candidates.trim() == "*" || candidates.split(',').any(|candidate| opaque(candidate) == opaque(etag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{exchange, replies, request, temp_dir};
+
+    #[test]
+    fn resolve_in_root_refuses_paths_that_escape() {
+        let dir = temp_dir("resolve");
+        fs::create_dir(dir.join("root")).unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+        let root = dir.join("root");
+        assert!(resolve_in_root(&root, "../secret").is_none());
+        assert!(resolve_in_root(&root, "a/../../secret").is_none());
+        assert_eq!(resolve_in_root(&root, "new.txt"), Some(root.canonicalize().unwrap().join("new.txt")));
+    }
+
+    #[test]
+    fn a_failed_write_leaves_what_was_there_and_no_temp_file() {
+        let dir = temp_dir("write-failure");
+        fs::write(dir.join("page.txt"), "original").unwrap();
+        write_file(&dir.join("page.txt"), b"replacement").unwrap();
+        assert_eq!(fs::read(dir.join("page.txt")).unwrap(), b"replacement");
+
+        // renaming over a non-empty directory fails after the temp file is written
+        fs::create_dir(dir.join("taken")).unwrap();
+        fs::write(dir.join("taken").join("inner.txt"), "original").unwrap();
+        assert!(write_file(&dir.join("taken"), b"replacement").is_err());
+        assert_eq!(fs::read(dir.join("taken").join("inner.txt")).unwrap(), b"original");
+        let mut names: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, ["page.txt", "taken"]);
+    }
+
+    #[test]
+    fn traversal_requests_get_404_without_reading_outside_the_root() {
+        let dir = temp_dir("traversal");
+        fs::create_dir(dir.join("root")).unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+        let root = dir.join("root");
+        let args = ["--directory", root.to_str().unwrap()];
+        for target in ["/files/..%2fsecret", "/files/%2e%2e/secret", "/files/a/..%2f..%2fsecret"] {
+            let replies = replies(&exchange(&args, &request("GET", target, &[], b"")));
+            assert_eq!(replies[0].status, 404, "{}", target);
+            assert!(!String::from_utf8_lossy(&replies[0].body).contains("secret"));
+        }
+        // a plain `..` above the root doesn't even make a valid target
+        let replies = replies(&exchange(&args, &request("GET", "/files/../../secret", &[], b"")));
+        assert_eq!(replies[0].status, 400);
+    }
+
+    #[test]
+    fn if_none_match_gets_304_only_while_the_etag_is_current() {
+        let dir = temp_dir("etag");
+        fs::write(dir.join("page.txt"), "hello").unwrap();
+        let args = ["--directory", dir.to_str().unwrap()];
+        let first = replies(&exchange(&args, &request("GET", "/files/page.txt", &[], b""))).remove(0);
+        assert_eq!(first.status, 200);
+        let etag = first.header("ETag").unwrap().to_string();
+        assert!(etag.starts_with("W/\""), "{}", etag);
+
+        let output = exchange(&args, &request("GET", "/files/page.txt", &[("If-None-Match", &etag)], b""));
+        assert!(output.starts_with(b"HTTP/1.1 304 Not Modified\r\n"), "{}", String::from_utf8_lossy(&output));
+        assert!(output.ends_with(b"\r\n\r\n"));
+
+        let stale = replies(&exchange(&args, &request("GET", "/files/page.txt", &[("If-None-Match", "W/\"0-0.0\"")], b""))).remove(0);
+        assert_eq!(stale.status, 200);
+        assert_eq!(stale.body, b"hello");
+    }
+
+    #[test]
+    fn etag_matches_compares_weakly_across_a_list() {
+        assert!(etag_matches("*", "W/\"1-2.3\""));
+        assert!(etag_matches("\"a\", W/\"1-2.3\"", "W/\"1-2.3\""));
+        assert!(etag_matches("\"1-2.3\"", "W/\"1-2.3\""));
+        assert!(!etag_matches("W/\"1-2.4\"", "W/\"1-2.3\""));
+    }
+
+    #[test]
+    fn vhosts_pick_the_root_by_host_and_fall_back_to_the_directory() {
+        let dir = temp_dir("vhost");
+        for (site, text) in [("default", "default site"), ("example", "example site")] {
+            fs::create_dir(dir.join(site)).unwrap();
+            fs::write(dir.join(site).join("index.txt"), text).unwrap();
+        }
+        let vhost = format!("example.com={}", dir.join("example").display());
+        let default = dir.join("default");
+        let args = ["--directory", default.to_str().unwrap(), "--vhost", &vhost];
+        let get = |host: &str| {
+            let raw = format!("GET /files/index.txt HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+            replies(&exchange(&args, raw.as_bytes())).remove(0).body
+        };
+        assert_eq!(get("example.com"), b"example site");
+        assert_eq!(get("Example.COM:8080"), b"example site");
+        assert_eq!(get("other.org"), b"default site");
+
+        // with no --directory, an unmatched host has nothing to serve
+        let args = ["--vhost", &vhost];
+        let raw = b"GET /files/index.txt HTTP/1.1\r\nHost: other.org\r\n\r\n";
+        assert_eq!(replies(&exchange(&args, raw))[0].status, 404);
+    }
+
+    #[test]
+    fn a_plus_in_a_file_name_is_a_plus() {
+        let dir = temp_dir("plus");
+        fs::write(dir.join("a+b.txt"), "plus").unwrap();
+        let args = ["--directory", dir.to_str().unwrap()];
+        for target in ["/files/a+b.txt", "/files/a%2Bb.txt"] {
+            let reply = replies(&exchange(&args, &request("GET", target, &[], b""))).remove(0);
+            assert_eq!(reply.body, b"plus", "{}", target);
+        }
+        let reply = replies(&exchange(&args, &request("POST", "/files/c+d.txt", &[], b"new"))).remove(0);
+        assert_eq!(reply.status, 201);
+        assert_eq!(fs::read(dir.join("c+d.txt")).unwrap(), b"new");
+        assert!(!dir.join("c d.txt").exists());
+    }
+}
@@ -0,0 +1,388 @@
+use std::{
+    io,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use crate::{
+    config::Config,
+    files::{document_root, file_path, resolve_in_root, serve_file, write_file},
+    json,
+    log::debug,
+    multipart,
+    proxy,
+    request::{path_decode, HttpMethod, Request},
+    response::Response,
+    router::{Params, Router},
+    server::Server,
+    sse::Clock,
+    websocket,
+};
+
+// OPTIONS is answered here rather than passed on.
+const PROXIED_METHODS: &[HttpMethod] = &[
+    HttpMethod::Get,
+    HttpMethod::Head,
+    HttpMethod::Post,
+    HttpMethod::Put,
+    HttpMethod::Delete,
+    HttpMethod::Patch,
+];
+
+// Every route the server answers, in the order they're tried.
+pub fn routes(config: &Config) -> Router {
+    use HttpMethod::*;
+
+    // liveness checks come first so no other route can shadow them
+    let mut router = Router::new()
+        .route(&[Get], "/health", health)
+        .route(&[Get], "/healthz", health);
+    if config.enable_metrics {
+        router = router.route(&[Get], "/metrics", metrics);
+    }
+    // proxied prefixes come before the built-in routes, so they can take one over
+    for (prefix, upstream) in &config.proxies {
+        let upstream = Arc::new(upstream.clone());
+        let exact = Arc::clone(&upstream);
+        router = router
+            .route(PROXIED_METHODS, prefix, move |request, _, server| {
+                Ok(proxy::forward(request, "", &exact, &server.config))
+            })
+            .route(PROXIED_METHODS, &format!("{}/*rest", prefix), move |request, params, server| {
+                Ok(proxy::forward(request, &params["rest"], &upstream, &server.config))
+            });
+    }
+    router = router
+        .route(&[Get], "/", |_, _, _| Ok(Response::ok()))
+        .route(&[Get], "/echo/*message", echo)
+        .route(&[Post], "/echo-json", echo_json)
+        .route(&[Get], "/user-agent", user_agent)
+        .route(&[Post], "/form", form)
+        .route(&[Get], "/events", events)
+        .route(&[Get], "/ws", |request, _, _| Ok(websocket::handshake(request)));
+    // with nowhere to serve them from, /files/ paths are like any unknown path
+    if config.directory.is_none() && config.vhosts.is_empty() {
+        return router;
+    }
+    router
+        .route(&[Get], "/files/*path", get_file)
+        .route(&[Post], "/files/*path", post_file)
+        .route(&[Put], "/files/*path", put_file)
+        .route(&[Delete], "/files/*path", delete_file)
+}
+
+fn health(_: &Request, _: &Params, _: &Server) -> io::Result<Response> {
+    Ok(Response::ok().header("Content-Type", "text/plain").body("ok"))
+}
+
+fn metrics(_: &Request, _: &Params, server: &Server) -> io::Result<Response> {
+    let metrics = server.metrics.render(server.active_connections.load(Ordering::SeqCst));
+    Ok(Response::ok()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(metrics))
+}
+
+fn echo(_: &Request, params: &Params, _: &Server) -> io::Result<Response> {
+    let message = path_decode(&params["message"]);
+    Ok(Response::ok().header("Content-Type", "text/plain").body(message.as_bytes()))
+}
+
+// Sends a JSON body back as it was, once it's checked to be well-formed.
+fn echo_json(request: &Request, _: &Params, _: &Server) -> io::Result<Response> {
+    let is_json = request.headers.get("content-type").is_some_and(|value| {
+        let media_type = value.split(';').next().unwrap_or("").trim();
+        media_type.eq_ignore_ascii_case("application/json")
+    });
+    if !is_json {
+        return Ok(Response::unsupported_media_type()
+            .header("Content-Type", "text/plain")
+            .body("expected application/json\n"));
+    }
+    Ok(match json::validate(&request.body) {
+        Ok(()) => Response::ok()
+            .header("Content-Type", "application/json")
+            .body(request.body.clone()),
+        Err(e) => Response::bad_request()
+            .header("Content-Type", "text/plain")
+            .body(format!("invalid JSON: {}\n", e)),
+    })
+}
+
+fn user_agent(request: &Request, _: &Params, _: &Server) -> io::Result<Response> {
+    Ok(match request.headers.get("user-agent") {
+        Some(user_agent) => Response::ok()
+            .header("Content-Type", "text/plain")
+            .body(user_agent.as_bytes()),
+        None => Response::bad_request()
+            .header("Content-Type", "text/plain")
+            .body("missing User-Agent header\n"),
+    })
+}
+
+// Sends a submitted form's fields back as `key=value` lines, sorted by key.
+fn form(request: &Request, _: &Params, _: &Server) -> io::Result<Response> {
+    let Some(form) = request.form() else {
+        return Ok(Response::unsupported_media_type()
+            .header("Content-Type", "text/plain")
+            .body("expected application/x-www-form-urlencoded\n"));
+    };
+    let mut fields: Vec<_> = form.into_iter().collect();
+    fields.sort();
+    let body: String = fields.iter().map(|(key, value)| format!("{}={}\n", key, value)).collect();
+    Ok(Response::ok().header("Content-Type", "text/plain").body(body))
+}
+
+// Server-Sent Events: the time, once a second, until the client hangs up.
+fn events(request: &Request, _: &Params, server: &Server) -> io::Result<Response> {
+    // the stream is sent chunked, which HTTP/1.0 clients can't read
+    if request.version != "HTTP/1.1" {
+        return Ok(Response::bad_request()
+            .header("Content-Type", "text/plain")
+            .body("event streams need HTTP/1.1\n"));
+    }
+    let clock = Clock::new(Duration::from_secs(1), Arc::clone(&server.shutdown));
+    Ok(Response::ok()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .stream(clock))
+}
+
+fn get_file(request: &Request, params: &Params, server: &Server) -> io::Result<Response> {
+    let config = &server.config;
+    match file_path(config, request, &params["path"]).filter(|path| path.exists()) {
+        Some(file_path) => serve_file(&file_path, request, config),
+        // a client-side route: let the app's own index page sort it out
+        None if config.spa && !looks_like_asset(&params["path"]) => match document_root(config, request) {
+            Some(directory) => serve_file(&directory.join(&config.index), request, config),
+            None => Ok(Response::not_found()),
+        },
+        None => Ok(Response::not_found()),
+    }
+}
+
+// A missing script or stylesheet should fail loudly rather than come back as
+// HTML, so anything with an extension counts.
+fn looks_like_asset(path: &str) -> bool {
+    Path::new(path).extension().is_some()
+}
+
+// Uploads need to say how long they are, one way or another.
+fn missing_length(request: &Request) -> Option<Response> {
+    if request.headers.contains_key("content-length") {
+        return None;
+    }
+    eprintln!("[{}] Content-Length missing from the {} request", request.id, request.method.as_str());
+    Some(
+        Response::bad_request()
+            .header("Content-Type", "text/plain")
+            .body("missing Content-Length\n"),
+    )
+}
+
+fn post_file(request: &Request, params: &Params, server: &Server) -> io::Result<Response> {
+    if let Some(response) = missing_length(request) {
+        return Ok(response);
+    }
+    let Some(file_path) = file_path(&server.config, request, &params["path"]) else {
+        return Ok(Response::not_found());
+    };
+    // an HTML upload form posts to a directory, carrying the files inside
+    if let Some(boundary) = request.headers.get("content-type").and_then(|value| multipart::boundary(value)) {
+        return Ok(upload_parts(request, &boundary, &file_path));
+    }
+
+    Ok(match write_file(&file_path, &request.body) {
+        Ok(()) => Response::created(),
+        Err(e) => {
+            eprintln!("[{}] Failed to write to file {}: {}", request.id, file_path.display(), e);
+            Response::not_found()
+        }
+    })
+}
+
+// Saves each file part of a `multipart/form-data` body into `directory` under
+// the name it was sent with, answering 201 with the names saved, one a line.
+fn upload_parts(request: &Request, boundary: &str, directory: &Path) -> Response {
+    let bad_request = |message: String| {
+        eprintln!("[{}] Refusing multipart upload: {}", request.id, message);
+        Response::bad_request()
+            .header("Content-Type", "text/plain")
+            .body(format!("{}\n", message))
+    };
+    if !directory.is_dir() {
+        return bad_request("multipart uploads go to a directory".to_string());
+    }
+    let parts = match multipart::parse(&request.body, boundary) {
+        Ok(parts) => parts,
+        Err(e) => return bad_request(e.to_string()),
+    };
+
+    let mut saved = String::new();
+    for part in parts {
+        let Some(filename) = part.filename.as_deref() else {
+            debug!("[{}] Ignoring form field {:?} in upload", request.id, part.name.as_deref().unwrap_or(""));
+            continue;
+        };
+        // only the last component counts, whichever separator the client's OS uses
+        let name = filename.rsplit(['/', '\\']).next().unwrap_or("");
+        // an empty file input still sends a part, with no name
+        if name.is_empty() || name == "." || name == ".." {
+            debug!("[{}] Ignoring upload with filename {:?}", request.id, filename);
+            continue;
+        }
+        let Some(path) = resolve_in_root(directory, name) else {
+            return bad_request(format!("filename {:?} is outside the directory", filename));
+        };
+        if let Err(e) = write_file(&path, part.content) {
+            eprintln!("[{}] Failed to write to file {}: {}", request.id, path.display(), e);
+            return Response::internal_server_error();
+        }
+        saved.push_str(name);
+        saved.push('\n');
+    }
+    if saved.is_empty() {
+        return bad_request("no files in the upload".to_string());
+    }
+    Response::created().header("Content-Type", "text/plain").body(saved)
+}
+
+// Unlike POST, PUT tells the client whether it created the file or replaced it
+fn put_file(request: &Request, params: &Params, server: &Server) -> io::Result<Response> {
+    if let Some(response) = missing_length(request) {
+        return Ok(response);
+    }
+    let Some(file_path) = file_path(&server.config, request, &params["path"]) else {
+        return Ok(Response::not_found());
+    };
+
+    let existed = file_path.is_file();
+    Ok(match write_file(&file_path, &request.body) {
+        Ok(()) if existed => Response::no_content(),
+        Ok(()) => Response::created(),
+        Err(e) => {
+            eprintln!("[{}] Failed to write to file {}: {}", request.id, file_path.display(), e);
+            Response::not_found()
+        }
+    })
+}
+
+fn delete_file(request: &Request, params: &Params, server: &Server) -> io::Result<Response> {
+    Ok(match file_path(&server.config, request, &params["path"]) {
+        Some(file_path) if file_path.is_dir() => {
+            eprintln!("[{}] Refusing to delete directory {}", request.id, file_path.display());
+            Response::forbidden()
+        }
+        Some(file_path) => match std::fs::remove_file(&file_path) {
+            Ok(()) => Response::no_content(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Response::not_found(),
+            Err(e) => {
+                eprintln!("[{}] Failed to delete file {}: {}", request.id, file_path.display(), e);
+                Response::forbidden()
+            }
+        },
+        None => Response::not_found(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::routes;
+    use crate::testing::{config, exchange, replies, request, temp_dir};
+
+    // the 8-byte signature and IHDR start of a PNG: not valid UTF-8
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR\x00\x00\x00\x01\xff\xfe\x00";
+
+    #[test]
+    fn binary_uploads_come_back_byte_for_byte() {
+        let dir = temp_dir("binary");
+        let args = ["--directory", dir.to_str().unwrap()];
+        let mut input = request("POST", "/files/x.png", &[], PNG);
+        input.extend(request("GET", "/files/x.png", &[], b""));
+        let replies = replies(&exchange(&args, &input));
+        assert_eq!(replies[0].status, 201);
+        assert_eq!(replies[1].status, 200);
+        assert_eq!(replies[1].header("Content-Type"), Some("image/png"));
+        assert_eq!(replies[1].body, PNG);
+        assert_eq!(fs::read(dir.join("x.png")).unwrap(), PNG);
+    }
+
+    #[test]
+    fn echo_leaves_the_query_out() {
+        let replies = replies(&exchange(&[], &request("GET", "/echo/foo?x=1", &[], b"")));
+        assert_eq!(replies[0].body, b"foo");
+    }
+
+    #[test]
+    fn header_names_are_found_whatever_their_case() {
+        let dir = temp_dir("header-case");
+        let args = ["--directory", dir.to_str().unwrap()];
+        let input = b"GET /user-agent HTTP/1.1\r\nhost: x\r\nuser-agent: curl/8.0\r\n\r\n\
+POST /files/a.txt HTTP/1.1\r\nHOST: x\r\ncontent-length: 5\r\n\r\nhello";
+        let replies = replies(&exchange(&args, input));
+        assert_eq!(replies[0].body, b"curl/8.0");
+        assert_eq!(replies[1].status, 201);
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn spa_mode_falls_back_to_the_index_but_not_for_assets() {
+        let dir = temp_dir("spa");
+        fs::write(dir.join("index.html"), "<p>app</p>").unwrap();
+        fs::write(dir.join("app.js"), "run()").unwrap();
+        let args = ["--directory", dir.to_str().unwrap(), "--spa"];
+        let get = |target| replies(&exchange(&args, &request("GET", target, &[], b""))).remove(0);
+
+        let route = get("/files/settings/profile");
+        assert_eq!(route.status, 200);
+        assert!(route.header("Content-Type").unwrap().starts_with("text/html"));
+        assert_eq!(route.body, b"<p>app</p>");
+        assert_eq!(get("/files/app.js").body, b"run()");
+        for asset in ["/files/missing.js", "/files/style.css", "/files/logo.png"] {
+            assert_eq!(get(asset).status, 404, "{}", asset);
+        }
+        // without --spa a missing page is just missing
+        let args = ["--directory", dir.to_str().unwrap()];
+        assert_eq!(replies(&exchange(&args, &request("GET", "/files/settings/profile", &[], b"")))[0].status, 404);
+    }
+
+    #[test]
+    fn a_two_part_multipart_upload_saves_both_files() {
+        let dir = temp_dir("multipart");
+        let args = ["--directory", dir.to_str().unwrap()];
+        let body = b"--XyZ\r\n\
+Content-Disposition: form-data; name=\"first\"; filename=\"a.txt\"\r\n\
+Content-Type: text/plain\r\n\r\n\
+alpha\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"second\"; filename=\"../b.bin\"\r\n\r\n\
+\x00\xffbeta\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+not a file\r\n\
+--XyZ--\r\n";
+        let headers = [("Content-Type", "multipart/form-data; boundary=XyZ")];
+        let replies = replies(&exchange(&args, &request("POST", "/files/", &headers, body)));
+        assert_eq!(replies[0].status, 201);
+        assert_eq!(replies[0].body, b"a.txt\nb.bin\n");
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"alpha");
+        // the path the client sent is dropped, keeping the upload in the directory
+        assert_eq!(fs::read(dir.join("b.bin")).unwrap(), b"\x00\xffbeta");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn files_routes_exist_only_with_somewhere_to_serve_from() {
+        assert!(routes(&config(&[])).routes().all(|(pattern, _)| !pattern.starts_with("/files/")));
+        for method in ["GET", "POST", "PUT", "DELETE"] {
+            let reply = replies(&exchange(&[], &request(method, "/files/a.txt", &[], b"x"))).remove(0);
+            assert_eq!(reply.status, 404, "{}", method);
+            assert_eq!(reply.header("Allow"), None);
+        }
+        let dir = temp_dir("no-root");
+        let args = ["--directory", dir.to_str().unwrap()];
+        assert!(routes(&config(&args)).routes().any(|(pattern, _)| pattern == "/files/*path"));
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    date::{clf_date, rfc3339},
+    json,
+    random,
+    request::Request,
+};
+
+// Longest X-Request-Id taken from a client.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// How much goes to stdout. Errors go to stderr whatever the level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    // startup and shutdown notices, plus the access log when it goes to stdout
+    Info,
+    // per-request dumps of headers and responses
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = ();
+
+    fn from_str(level: &str) -> Result<LogLevel, ()> {
+        match level {
+            "error" => Ok(LogLevel::Error),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+// How access log entries are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    // Common Log Format
+    Text,
+    // one object per line, for log aggregators
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(format: &str) -> Result<LogFormat, ()> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+// Global rather than threaded through, so request parsing can log too
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+// println! that only prints at the info level and up
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::Info) {
+            println!($($arg)*);
+        }
+    };
+}
+
+// println! that only prints at the debug level
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::Debug) {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {debug, info};
+
+// One line per response, in Common Log Format or as JSON, to stdout or an
+// appended file.
+pub struct AccessLog {
+    out: Mutex<Box<dyn Write + Send>>,
+    to_stdout: bool,
+    format: LogFormat,
+}
+
+impl AccessLog {
+    pub fn open(path: Option<&Path>, format: LogFormat) -> io::Result<AccessLog> {
+        let out: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+            None => Box::new(io::stdout()),
+        };
+        Ok(AccessLog {
+            out: Mutex::new(out),
+            to_stdout: path.is_none(),
+            format,
+        })
+    }
+
+    // `client` is the peer's IP and `request_line` the request's first line as
+    // sent. An empty body is logged as `-`, as CLF has it. `duration` is how
+    // long the request took to answer and `request_id` what its X-Request-Id
+    // said, both of which the text format tacks on the end.
+    pub fn record(
+        &self,
+        client: &str,
+        request_line: &str,
+        status: u16,
+        body_length: u64,
+        duration: Duration,
+        request_id: &str,
+    ) {
+        // a log file was asked for explicitly, but stdout quiets down with the level
+        if self.to_stdout && !enabled(LogLevel::Info) {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Text => {
+                let bytes = match body_length {
+                    0 => "-".to_string(),
+                    n => n.to_string(),
+                };
+                format!(
+                    "{} - - [{}] \"{}\" {} {} {:.3} {}\n",
+                    client,
+                    clf_date(SystemTime::now()),
+                    request_line,
+                    status,
+                    bytes,
+                    duration.as_secs_f64() * 1000.0,
+                    request_id
+                )
+            }
+            LogFormat::Json => {
+                // a request that never parsed may not have a method or target,
+                // and one turned away unread has no line at all
+                let mut parts = request_line.split_whitespace();
+                let (method, path) = match request_line {
+                    "-" => (None, None),
+                    _ => (parts.next(), parts.next()),
+                };
+                format!(
+                    "{{\"timestamp\":{},\"request_id\":{},\"remote_addr\":{},\"method\":{},\"path\":{},\"status\":{},\"bytes\":{},\"duration_ms\":{:.3}}}\n",
+                    json::string(&rfc3339(SystemTime::now())),
+                    json::string(request_id),
+                    json::string(client),
+                    method.map_or("null".to_string(), json::string),
+                    path.map_or("null".to_string(), json::string),
+                    status,
+                    body_length,
+                    duration.as_secs_f64() * 1000.0
+                )
+            }
+        };
+        // a poisoned lock only means another thread panicked mid-write
+        let mut out = self.out.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|_| out.flush()) {
+            eprintln!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+// A fresh ID for a request that didn't bring its own.
+pub fn new_request_id() -> String {
+    random::hex_token()
+}
+
+// The client's own X-Request-Id, so one ID can follow a request across
+// services, as long as it's a sensible token to put in logs; a fresh one
+// otherwise.
+pub fn request_id(request: &Request) -> String {
+    match request.headers.get("x-request-id") {
+        Some(id) if (1..=MAX_REQUEST_ID_LENGTH).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic()) => {
+            id.clone()
+        }
+        _ => new_request_id(),
+    }
+}
+
+// The first line of a raw request, for the log; whatever arrived is used even
+// when it didn't parse, with anything unprintable written as `\xHH` so it
+// can't break the log line up.
+pub fn request_line(raw: &[u8]) -> String {
+    let end = raw.windows(2).position(|w| w == b"\r\n").unwrap_or(raw.len());
+    raw[..end]
+        .iter()
+        .map(|&b| match b {
+            b' '..=b'~' => (b as char).to_string(),
+            b => format!("\\x{:02x}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_line_escapes_what_would_break_the_log_line() {
+        assert_eq!(request_line(b"GET /a?x\x01y\nInjected: 1 HTTP/1.1\r\nHost: x"), "GET /a?x\\x01y\\x0aInjected: 1 HTTP/1.1");
+        assert_eq!(request_line(b"GET /caf\xc3\xa9 HTTP/1.1"), "GET /caf\\xc3\\xa9 HTTP/1.1");
+    }
+}
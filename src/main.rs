@@ -1,119 +1,519 @@
-#[allow(unused_imports)]
-use std::net::{TcpListener, TcpStream};
-use std::{collections::HashMap, io::{BufReader, Read, Write}, path::Path, time::Duration};
-use rayon::{vec, ThreadPoolBuilder};
-use std::{fs::File, env};
-
-
-const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\n\r\n";
-const NOT_FOUND_RESPONSE: &str = "HTTP/1.1 404 Not Found\r\n\r\n";
-const BAD_REQUEST_RESPONSE: &str = "HTTP/1.1 400 Bad Request\r\n\r\n";
-const BIND_ADDRESS: &str = "127.0.0.1:4221";
-const RESOURCE_CREATED: &str = "HTTP/1.1 201 Created\r\n\r\n";
-
-fn extract_headers(request: &str) -> HashMap<String,String> {
-    let mut headers = HashMap::new();
-    let mut splitted_request = request.split("\r\n");
-
-    if let Some(status) = splitted_request.next() {
-        let splitted_status: Vec<&str> = status.splitn(3," ").collect();
-        if splitted_status.len() == 3 {
-            headers.insert("Type".to_string(), splitted_status[0].to_string());
-            headers.insert("Route".to_string(), splitted_status[1].to_string());
-            headers.insert("Version".to_string(), splitted_status[2].to_string());
-        } else {
-            eprintln!("Malformed status line: {}", status);
+mod auth;
+mod base64;
+mod cidr;
+mod compression;
+mod config;
+mod date;
+mod files;
+mod handlers;
+mod json;
+mod log;
+mod metrics;
+mod middleware;
+mod negotiate;
+mod multipart;
+mod net;
+mod proxy;
+mod random;
+mod rate_limit;
+mod request;
+mod response;
+mod router;
+mod server;
+mod sse;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod websocket;
+
+use std::{backtrace::Backtrace, fs, io::{Read, Write}, path::Path, panic::{self, AssertUnwindSafe}, sync::{atomic::Ordering, Arc}, thread, time::Instant};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use config::Config;
+use log::{debug, info, request_line};
+use request::{read_request, wait_for_request, HttpMethod, ParseError, ReadError, Request};
+use response::Response;
+use router::{Match, Router};
+use server::Server;
+use net::{Connection, Listener, ReadTimeout};
+use std::env;
+
+// seconds a client turned away at capacity is told to wait
+const CAPACITY_RETRY_AFTER: u64 = 1;
+
+
+fn main() -> Result<(),std::io::Error> {
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid arguments: {}", e);
+            std::process::exit(2);
         }
+    };
+    log::set_level(config.log_level);
+    // panics are caught and answered with 500, so make sure they leave enough
+    // behind to debug them whatever RUST_BACKTRACE says
+    panic::set_hook(Box::new(|info| {
+        let thread = thread::current();
+        let name = thread.name().unwrap_or("<unnamed>");
+        eprintln!("Thread {} {}\n{}", name, info, Backtrace::force_capture());
+    }));
+
+    let router = handlers::routes(&config);
+    if config.list_routes {
+        print!("{}", route_list(&router));
+        return Ok(());
+    }
+    if let Some(directory) = config.directory.as_ref().filter(|directory| !directory.is_dir()) {
+        eprintln!("--directory {} is not a directory", directory.display());
+        std::process::exit(2);
+    }
+    if let Some((host, root)) = config.vhosts.iter().find(|(_, root)| !root.is_dir()) {
+        eprintln!("--vhost {}={} is not a directory", host, root.display());
+        std::process::exit(2);
     }
+    let middleware = middleware::stack(&config);
+    let server = match Server::new(config, router, middleware) {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            eprintln!("Failed to start: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let config = &server.config;
 
-    for split in splitted_request {
-        if let Some((key, value)) = split.split_once(':') {
-            headers.insert(
-                key.trim().to_string(),
-                value.trim().to_string(), // Trim whitespace
-            );
-       } else if !split.is_empty() { // Ignore empty lines but log others
-           eprintln!("Malformed header encountered: {}", split);
-       }
+    let address = match &config.unix {
+        Some(path) => path.display().to_string(),
+        // IPv6 addresses need brackets to keep the port apart
+        None if config.host.contains(':') => format!("[{}]:{}", config.host, config.port),
+        None => format!("{}:{}", config.host, config.port),
+    };
+    let listener = match Listener::bind(config) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind {}: {}", address, e);
+            std::process::exit(1);
+        }
+    };
+    let redirect = match config.redirect_http {
+        Some(port) => match Listener::bind_port(config, port) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                eprintln!("Failed to bind redirect port {}: {}", port, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let pool = match ThreadPoolBuilder::new().num_threads(config.threads).build() {
+        Ok(answer) => Arc::new(answer),
+        Err(e) => panic!("Failed to build thread pool: {:?}", e)
+    };
+    if !config.quiet {
+        info!("{}", startup_summary(config, &address));
     }
-    headers
-}
 
-fn main() -> Result<(),std::io::Error> {
-    // You can use print statements as follows for debugging, they'll be visible when running tests.
-    println!("Logs from your program will appear here!");
+    let signalled = Arc::clone(&server);
+    if let Err(e) = ctrlc::set_handler(move || signalled.shutdown.store(true, Ordering::SeqCst)) {
+        eprintln!("Failed to install signal handler: {}", e);
+    }
 
-    let listener = TcpListener::bind(BIND_ADDRESS)?;
-    let pool = match ThreadPoolBuilder::new().num_threads(8).build() {
-        Ok(answer) => answer,
-        Err(e) => panic!("Failed to build thread pool: {:?}", e)
+    let redirector = match redirect {
+        Some(redirect) => {
+            let server = Arc::clone(&server);
+            let pool = Arc::clone(&pool);
+            let spawned = thread::Builder::new()
+                .name("redirect".to_string())
+                .spawn(move || redirect_loop(redirect, &server, &pool))?;
+            Some(spawned)
+        }
+        None => None,
     };
-    
-    for stream in listener.incoming() {
-         match stream {
-             Ok(stream) => {
+
+    // Accept without blocking so the loop notices a shutdown request promptly
+    listener.set_nonblocking(true)?;
+    let mut failure = None;
+    while !server.is_shutting_down() {
+         match listener.accept() {
+             Ok(connection) => {
+                // refused before it can take a worker; dropping it closes the socket
+                if let Some(ip) = connection.peer_ip() {
+                    if !cidr::permitted(ip, &config.allow, &config.deny) {
+                        info!("Refused connection from {}", ip);
+                        continue;
+                    }
+                }
+                // at capacity, answer straight from the accept loop rather than
+                // queueing on the pool without bound
+                let active = server.active_connections.load(Ordering::SeqCst);
+                if config.max_connections != 0 && active >= config.max_connections {
+                    turn_away(connection, &server);
+                    continue;
+                }
+                server.active_connections.fetch_add(1, Ordering::SeqCst);
+                let server = Arc::clone(&server);
                 pool.spawn(move || {
-                    if let Err(e) = handle_client(stream) {
-                        eprintln!("Error handling connection: {}", e);
+                    match isolate_panics(|| serve_connection(connection, &server)) {
+                        Ok(()) => {},
+                        // hanging up mid-response is how event stream clients say goodbye
+                        Err(e) if matches!(e.kind(), std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset) => {
+                            debug!("Client went away: {}", e);
+                        },
+                        // a stalled write, already reported by send_response
+                        Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                            debug!("Connection timed out: {}", e);
+                        },
+                        Err(e) => eprintln!("Error handling connection: {}", e),
                     }
+                    server.active_connections.fetch_sub(1, Ordering::SeqCst);
                 });
                 },
-             Err(e) => {
-                 println!("error: {}", e);
+             Err(e) => match net::accept_backoff(&e) {
+                 Some(pause) => {
+                     if e.kind() != std::io::ErrorKind::WouldBlock {
+                         eprintln!("Failed to accept connection: {}", e);
+                     }
+                     thread::sleep(pause);
+                 },
+                 None => {
+                     eprintln!("Listener failed, shutting down: {}", e);
+                     failure = Some(e);
+                     break;
+                 }
              }
          }
      }
-    Ok(())
+
+    info!(
+        "Shutting down, waiting for {} connection(s) to finish",
+        server.active_connections.load(Ordering::SeqCst)
+    );
+    drop(listener);
+    if let Some(redirector) = redirector {
+        let _ = redirector.join();
+    }
+    // returning from main ends the process, and any connection still open with it
+    let abandoned = server.wait_for_connections(config.shutdown_timeout);
+    if abandoned > 0 {
+        eprintln!("Shutdown timeout reached, force-closing {} connection(s)", abandoned);
+    }
+    info!("Shutdown complete");
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// What an operator needs to confirm the server came up as intended.
+fn startup_summary(config: &Config, address: &str) -> String {
+    let root = match &config.directory {
+        Some(directory) => directory.display().to_string(),
+        None if config.vhosts.is_empty() => "none (/files/ routes are disabled)".to_string(),
+        None => "none (/files/ only for the vhosts)".to_string(),
+    };
+    let tls = match &config.tls_cert {
+        Some(cert) => format!("on ({})", cert.display()),
+        None => "off".to_string(),
+    };
+    let mut summary = format!(
+        "Listening on {}\n  threads:   {}\n  root:      {}\n  tls:       {}\n  log level: {}",
+        address,
+        config.threads,
+        root,
+        tls,
+        config.log_level.as_str()
+    );
+    if !config.vhosts.is_empty() {
+        let hosts: Vec<&str> = config.vhosts.iter().map(|(host, _)| host.as_str()).collect();
+        summary.push_str(&format!("\n  vhosts:    {}", hosts.join(", ")));
+    }
+    if let Some(port) = config.redirect_http {
+        summary.push_str(&format!("\n  redirect:  http port {} to https", port));
+    }
+    summary
+}
+
+// Accepts on the `--redirect-http` port until shutdown, handing connections to
+// the pool the way the main loop does.
+fn redirect_loop(listener: Listener, server: &Arc<Server>, pool: &ThreadPool) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        eprintln!("Failed to set up redirect listener: {}", e);
+        return;
+    }
+    while !server.is_shutting_down() {
+        match listener.accept() {
+            Ok(connection) => {
+                if let Some(ip) = connection.peer_ip() {
+                    if !cidr::permitted(ip, &server.config.allow, &server.config.deny) {
+                        info!("Refused connection from {}", ip);
+                        continue;
+                    }
+                }
+                server.active_connections.fetch_add(1, Ordering::SeqCst);
+                let server = Arc::clone(server);
+                pool.spawn(move || {
+                    if let Err(e) = isolate_panics(|| serve_redirect(connection, &server)) {
+                        debug!("Redirect connection ended: {}", e);
+                    }
+                    server.active_connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(e) => match net::accept_backoff(&e) {
+                Some(pause) => {
+                    if e.kind() != std::io::ErrorKind::WouldBlock {
+                        eprintln!("Failed to accept redirect connection: {}", e);
+                    }
+                    thread::sleep(pause);
+                }
+                None => {
+                    eprintln!("Redirect listener failed: {}", e);
+                    return;
+                }
+            },
+        }
+    }
+}
+
+// One line per route for --list-routes: its pattern, then its methods. OPTIONS
+// isn't listed against each, since every path answers it.
+fn route_list(router: &Router) -> String {
+    let width = router.routes().map(|(pattern, _)| pattern.len()).max().unwrap_or(0);
+    let mut list = String::new();
+    for (pattern, methods) in router.routes() {
+        let methods: Vec<&str> = methods.iter().map(|method| method.as_str()).collect();
+        list.push_str(&format!("{:<width$}  {}\n", pattern, methods.join(", ")));
+    }
+    list
+}
+
+// Tells a connection that arrived while every slot was taken to come back
+// later. Its request is never read, and a TLS client hasn't shaken hands yet,
+// so it just gets closed.
+fn turn_away(mut connection: Connection, server: &Server) {
+    let started = Instant::now();
+    let client = connection.client();
+    info!("At capacity, turning away {}", client);
+    if server.tls.is_some() {
+        return;
+    }
+    let response = Response::service_unavailable().header("Retry-After", &CAPACITY_RETRY_AFTER.to_string());
+    // non-blocking, so a client that can't take a few hundred bytes is
+    // dropped instead of stalling the accept loop. Linux doesn't hand the
+    // listener's mode on to accepted sockets, so it has to be set here.
+    let sent = connection
+        .set_nonblocking(true)
+        .and_then(|()| send_response(&mut connection, server, response, false, true, &client, "-", started));
+    if let Err(e) = sent {
+        debug!("Couldn't send 503 to {}: {}", client, e);
+    }
+    connection.shutdown_write();
+}
+
+// Runs one connection's work on a worker. A panic escaping it (from reading a
+// request, say, or a body as it streams) costs that connection instead of
+// the process, which rayon aborts on a panicking job. The panic hook has
+// already logged it, and unwinding drops the socket, which closes it.
+fn isolate_panics(work: impl FnOnce() -> std::io::Result<()>) -> std::io::Result<()> {
+    panic::catch_unwind(AssertUnwindSafe(work))
+        .unwrap_or_else(|_| Err(std::io::Error::other("connection handler panicked")))
+}
+
+// Sets up an accepted socket, wrapping it in TLS when serving HTTPS, and runs
+// its requests.
+fn serve_connection(connection: Connection, server: &Server) -> Result<(),std::io::Error> {
+    let client = connection.client();
+    // a read that hears nothing for this long times out, and so does a
+    // write to a client that has stopped reading
+    connection.set_read_timeout(server.config.read_timeout)?;
+    connection.set_write_timeout(server.config.write_timeout)?;
+    connection.set_nodelay(server.config.no_delay)?;
+    let mut connection = match &server.tls {
+        Some(tls) => connection.start_tls(tls)?,
+        None => connection,
+    };
+    let result = handle_client(&mut connection, &client, server, handle_request);
+    connection.close();
+    result
+}
+
+// Like `serve_connection`, for the plain HTTP port of `--redirect-http`, where
+// every request is sent on to HTTPS.
+fn serve_redirect(mut connection: Connection, server: &Server) -> Result<(),std::io::Error> {
+    let client = connection.client();
+    connection.set_read_timeout(server.config.read_timeout)?;
+    connection.set_write_timeout(server.config.write_timeout)?;
+    connection.set_nodelay(server.config.no_delay)?;
+    handle_client(&mut connection, &client, server, redirect_to_https)
 }
 
-fn handle_client(mut stream: TcpStream) -> Result<(),std::io::Error>{
+// Runs the keep-alive loop over any byte stream: TCP, TLS or a Unix socket,
+// answering each request with `respond`. The caller sets the stream's read
+// timeout up; between requests it's cut short to what's left of the
+// keep-alive timeout, and put back once the next request starts.
+fn handle_client<S: Read + Write + ReadTimeout>(stream: &mut S, client: &str, server: &Server, respond: Respond) -> Result<(),std::io::Error>{
+    let config = &server.config;
+    let mut pending: Vec<u8> = Vec::new();
+    let mut idle_since = Instant::now();
+    let mut served = 0;
+    // whether the request being read has had its `100 Continue`; reads that
+    // time out part-way through are retried, and it should go out only once
+    let mut continue_sent = false;
     loop{
-        let mut close = false;
-        let mut reader = BufReader::new(&stream);
-        stream
-            .set_read_timeout(Some(Duration::new(0, 100000000)))
-            .expect("Timeout handled");
-
-        let mut buf: [u8; 1024] = [0; 1024];
-
-        let bytes_read = match reader.read(&mut buf) {
-            Ok(0) => {
-                println!("Client Disconnectd");
-                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Disconnected"));
+        // timed from the request's first bytes, body included, to its response
+        // going out; the idle wait before it doesn't count
+        let mut started = Instant::now();
+        // after a response, wait for the next request no longer than the
+        // connection has left to idle
+        let idle_left = config.keep_alive_timeout.saturating_sub(idle_since.elapsed());
+        let shortened = served > 0 && pending.is_empty() && idle_left < config.read_timeout;
+        if shortened {
+            if idle_left.is_zero() {
+                debug!("Closing idle connection from {} after {} request(s)", client, served);
+                break;
+            }
+            stream.set_read_timeout(idle_left)?;
+        }
+        let read = wait_for_request(stream, &mut pending).and_then(|()| {
+            if shortened {
+                stream.set_read_timeout(config.read_timeout)?;
+            }
+            started = Instant::now();
+            read_request(stream, &mut pending, config, &mut continue_sent)
+        });
+        let length = match read {
+            Ok(length) => {
+                idle_since = Instant::now();
+                length
             },
-            Ok(n) => n,
-            Err(e) => {
+            Err(ReadError::BodyTooLarge(length)) => {
+                eprintln!("Refusing a {} byte body, the limit is {}", length, config.max_body_size);
+                // the unread body is still on the wire, so this connection is done
+                send_response(stream, server, Response::payload_too_large(), false, true, client, &request_line(&pending), started)?;
+                break;
+            },
+            Err(ReadError::RequestLineTooLong(limit)) => {
+                eprintln!("Refusing a request line over {} bytes", limit);
+                // the target is still arriving, and too long to be worth logging
+                send_response(stream, server, Response::uri_too_long(), false, true, client, "-", started)?;
+                break;
+            },
+            Err(ReadError::HeadersTooLarge(limit)) => {
+                eprintln!("Refusing a header section over {} bytes", limit);
+                // the rest of the headers are still coming, so this connection is done
+                let response = Response::request_header_fields_too_large();
+                send_response(stream, server, response, false, true, client, &request_line(&pending), started)?;
+                break;
+            },
+            Err(ReadError::TooManyHeaders(limit)) => {
+                eprintln!("Refusing a request with more than {} headers", limit);
+                // a body may follow that we won't read, so close here too
+                let response = Response::request_header_fields_too_large();
+                send_response(stream, server, response, false, true, client, &request_line(&pending), started)?;
+                break;
+            },
+            Err(ReadError::InvalidFraming(reason)) => {
+                eprintln!("Refusing a request with invalid framing: {}", reason);
+                // we can't tell where this request ends, so neither the next one
+                let response = Response::bad_request()
+                    .header("Content-Type", "text/plain")
+                    .body(format!("{}\n", reason));
+                send_response(stream, server, response, false, true, client, &request_line(&pending), started)?;
+                break;
+            },
+            Err(ReadError::Parse(e)) => {
+                eprintln!("Failed to parse request: {}", e);
+                // the headers that frame the body can't be trusted either
+                let response = Response::bad_request()
+                    .header("Content-Type", "text/plain")
+                    .body(format!("{}\n", e));
+                send_response(stream, server, response, false, true, client, &request_line(&pending), started)?;
+                break;
+            },
+            Err(ReadError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                // there's no one left to answer, so this never reaches the access log
+                if pending.is_empty() {
+                    debug!("{} closed the connection after {} request(s)", client, served);
+                } else {
+                    info!("{} disconnected part-way through a request after {} request(s)", client, served);
+                }
+                break;
+            },
+            Err(ReadError::Io(e)) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                // part of a request arrived and then nothing for a whole read timeout
+                if !pending.is_empty() {
+                    debug!("Closing connection from {} stalled mid-request", client);
+                    send_response(stream, server, Response::request_timeout(), false, true, client, &request_line(&pending), started)?;
+                    break;
+                }
+                // let an idle connection go once shutdown starts
+                if server.is_shutting_down() {
+                    debug!("Closing idle connection from {} for shutdown after {} request(s)", client, served);
+                    break;
+                }
+                // between requests, the keep-alive timeout decides how long to wait
+                if idle_since.elapsed() >= config.keep_alive_timeout {
+                    debug!("Closing idle connection from {} after {} request(s)", client, served);
+                    break;
+                }
+                continue;
+            },
+            Err(ReadError::Io(e)) => {
                 eprintln!("Failed to read from stream: {}", e);
                 return Err(e);
             }
         };
     
-        let request = String::from_utf8_lossy(&buf[..bytes_read]);
-
-        if request.contains("Connection: close"){
-            close = true;
-        }
-
-        match handle_request(request.into_owned()) {
-            Ok(mut response) => {
-                if close {
-                    // split off the header block vs. the body
-                    let mut parts = response.splitn(2, "\r\n\r\n");
-                    let head = parts.next().unwrap_or("");
-                    let body = parts.next().unwrap_or("");
-                    // re‑assemble with Connection: close
-                    response = format!(
-                        "{}\r\nConnection: close\r\n\r\n{}",
-                        head, body
-                    );
+        served += 1;
+        // one client shouldn't get to keep a worker forever
+        let limit_reached = config.max_requests_per_connection != 0 && served >= config.max_requests_per_connection;
+        let request = &pending[..length];
+        let (head, close, line, response) = match Request::parse(request) {
+            Ok(mut request) => {
+                let line = format!("{} {} {}", request.method.as_str(), request.target, request.version);
+                let head = request.method == HttpMethod::Head;
+                request.id = log::request_id(&request);
+                let id = request.id.clone();
+                // a handler that fails or panics rather than answering gets its
+                // client a 500, and the access log a line, like any other request
+                let failed = || Response::internal_server_error().with_default_body(accept(&request));
+                // a bug in one handler costs its request, not the worker or the client's wait
+                match panic::catch_unwind(AssertUnwindSafe(|| respond(&request, client, server))) {
+                    Ok(Ok(response)) => {
+                        let close = request.wants_close() || server.is_shutting_down() || limit_reached;
+                        (head, close, line, with_request_id(response, || id))
+                    },
+                    Ok(Err(e)) => {
+                        eprintln!("[{}] Error in handle_request: {}", id, e);
+                        (head, true, line, with_request_id(failed(), || id))
+                    },
+                    Err(_) => (head, true, line, with_request_id(failed(), || id)),
                 }
-                // write the (possibly modified) response back to the client
-                stream.write_all(response.as_bytes())?;
+            },
+            // after a request we can't make sense of, the framing of the next one can't be trusted either
+            Err(ParseError::UnknownMethod(method)) => {
+                eprintln!("Unknown method: {}", method);
+                (false, true, request_line(request), Response::not_implemented())
+            },
+            Err(ParseError::UnsupportedVersion(version)) => {
+                eprintln!("Unsupported HTTP version: {}", version);
+                (false, true, request_line(request), Response::http_version_not_supported())
             },
             Err(e) => {
-                eprintln!("Error in handle_request: {}", e);
-                break;
+                eprintln!("Failed to parse request: {}", e);
+                let response = Response::bad_request()
+                    .header("Content-Type", "text/plain")
+                    .body(format!("{}\n", e));
+                (false, true, request_line(request), response)
             }
+        };
+
+        // keep the buffer, and whatever of the next request is already in it
+        pending.drain(..length);
+        continue_sent = false;
+        let upgraded = response.status == 101;
+        send_response(stream, server, response, head, close, client, &line, started)?;
+        // from here on the connection speaks WebSocket, not HTTP
+        if upgraded {
+            return websocket::echo(stream, std::mem::take(&mut pending), server);
         }
         if close{
             break;
@@ -122,88 +522,280 @@ fn handle_client(mut stream: TcpStream) -> Result<(),std::io::Error>{
     Ok(())
 }
 
-fn handle_request(request: String) -> Result<String,std::io::Error>{
-    let headers = extract_headers(&request);
-    println!("{:#?}",headers);
+// Every response leaves through here, so headers that describe the server or
+// the connection rather than the request are added in one place, error pages
+// (or default error bodies) are filled in, answers to HEAD lose their body,
+// and each one gets its access log line.
+#[allow(clippy::too_many_arguments)]
+fn send_response<W: Write>(
+    stream: &mut W,
+    server: &Server,
+    response: Response,
+    head: bool,
+    close: bool,
+    client: &str,
+    request_line: &str,
+    started: Instant,
+) -> Result<(),std::io::Error> {
+    let response = match server.config.error_pages.get(&response.status) {
+        Some(page) => with_error_page(response, page),
+        None => response,
+    }
+    // anything that reached here without one couldn't be negotiated
+    .with_default_body(None);
+    let response = if head { response.without_body() } else { response };
+    let response = response.header("Server", &server.config.server_name);
+    // answers sent before a request could be parsed still get an ID to quote
+    let response = with_request_id(response, log::new_request_id);
+    let request_id = response.header_value("X-Request-Id").unwrap_or("-").to_string();
+    // a 101 already says the connection is being upgraded
+    let response = match response.status {
+        101 => response,
+        _ => response.header("Connection", if close { "close" } else { "keep-alive" }),
+    };
+    debug!("{}", response.head());
+    let (status, body_length) = (response.status, response.body.len());
+    // write the response back to the client
+    let result = response.write_to(stream).and_then(|_| stream.flush());
+    if let Err(e) = &result {
+        if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) {
+            // the rest of the response is lost, so the connection goes with it
+            eprintln!("Abandoning a response to {}, which took nothing for {:?}", client, server.config.write_timeout);
+        }
+    }
+    // recorded once written, so the duration covers sending it, even if that failed
+    let duration = started.elapsed();
+    server.access_log.record(client, request_line, status, body_length, duration, &request_id);
+    server.metrics.record(status, body_length, duration);
+    result
+}
 
-    let response = match (headers.get("Type").map(|s| s.as_str()), headers.get("Route").map(|s| s.as_str())) {
-        (Some("GET"), Some("/")) => {
-            OK_RESPONSE.to_string()
-        },
-        (Some("GET"), Some(route)) if route.starts_with("/echo/") => {
-            if let Some(param) = route.strip_prefix("/echo/") {
-                format!(
-                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
-                    param.len(),
-                    param
-                )
-            } else {
-                BAD_REQUEST_RESPONSE.to_string()
-            }
-        },
-        (Some("GET"), Some("/user-agent")) => {
-            if let Some(user_agent) = headers.get("User-Agent") {
-                format!(
-                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
-                    user_agent.len(),
-                    user_agent
-                )
-            } else {
-                BAD_REQUEST_RESPONSE.to_string()
-            }
-        },
-        (Some("GET"), Some(route)) if route.starts_with("/files/") => {
-            if let Some(file_name) = route.strip_prefix("/files/") {
-                let env_args: Vec<String> = env::args().collect();
-                let mut dir = env_args[2].clone();
-                dir.push_str(file_name);
-                match std::fs::read(&dir) {
-                    Ok(content) => {
-                        format!(
-                            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n{}",
-                            content.len(),
-                            String::from_utf8_lossy(&content)
-                        )
-                    },
-                    Err(_) => NOT_FOUND_RESPONSE.to_string()
-                }
-            } else {
-                NOT_FOUND_RESPONSE.to_string()
-            }
-        },
-        (Some("POST"), Some(route)) if route.starts_with("/files/") => {
-            let env_args: Vec<String> = env::args().collect();
-            let dir_name = &env_args[2];
-            let filename = route.strip_prefix("/files/").expect("Error while stripping file");
-            let file_path = Path::new(dir_name).join(filename);
-            
-            // Properly split the request into headers and body using \r\n\r\n separator
-            let parts: Vec<&str> = request.split("\r\n\r\n").collect();
-            if parts.len() > 1 {
-                let body = parts[1];
-                
-                match File::create(&file_path) {
-                    Ok(mut file) => {
-                        if let Err(e) = file.write_all(body.as_bytes()) {
-                            eprintln!("Failed to write to file {}: {}", file_path.display(), e);
-                            NOT_FOUND_RESPONSE.to_string()
-                        } else {
-                            RESOURCE_CREATED.to_string()
-                        }
-                    },
-                    Err(e) => {
-                        eprintln!("Failed to create file {}: {}", file_path.display(), e);
-                        NOT_FOUND_RESPONSE.to_string()
-                    }
-                }
-            } else {
-                eprintln!("Request body not found in the POST request");
-                NOT_FOUND_RESPONSE.to_string()
-            }
-        },
-        _ => NOT_FOUND_RESPONSE.to_string(), // default response for any other method/route
+// How the requests on a connection get answered.
+type Respond = fn(&Request, &str, &Server) -> Result<Response,std::io::Error>;
+
+// Sends a request on the plain HTTP port to the same host and target over
+// HTTPS.
+fn redirect_to_https(request: &Request, _: &str, server: &Server) -> Result<Response,std::io::Error> {
+    // without Host there's no telling which name the client used
+    let Some(host) = request.host() else {
+        return Ok(Response::bad_request()
+            .header("Content-Type", "text/plain")
+            .body("missing Host header\n"));
     };
-    println!("{}",response);
+    // IPv6 addresses need brackets to keep the port apart
+    let host = if host.contains(':') { format!("[{}]", host) } else { host.to_string() };
+    let authority = match server.config.port {
+        443 => host,
+        port => format!("{}:{}", host, port),
+    };
+    Ok(Response::moved_permanently(&format!("https://{}{}", authority, request.target)))
+}
 
-    Ok(response)
+fn handle_request(request: &Request, client: &str, server: &Server) -> Result<Response,std::io::Error>{
+    let response = middleware::run(server, client, request, &|request| {
+        // every route answers OPTIONS, so the router doesn't need telling
+        if request.method == HttpMethod::Options {
+            return Ok(options(request, server));
+        }
+        match server.router.find(request.method, &request.path) {
+            Match::Found(handler, params) => handler(request, &params, server),
+            // the route exists, just not for this method
+            Match::MethodNotAllowed(methods) => {
+                eprintln!("[{}] {} is not allowed on {}", request.id, request.method.as_str(), request.path);
+                Ok(Response::method_not_allowed().header("Allow", &allow_header(methods)))
+            },
+            Match::NotFound => Ok(match &server.config.not_found_file {
+                Some(page) => with_error_page(Response::not_found(), page),
+                None => Response::not_found(), // default response for any other route
+            }),
+        }
+    })?;
+    Ok(response.with_default_body(accept(request)))
+}
+
+fn accept(request: &Request) -> Option<&str> {
+    request.headers.get("accept").map(String::as_str)
+}
+
+// `OPTIONS *` asks about the server as a whole, `OPTIONS /path` about one route.
+fn options(request: &Request, server: &Server) -> Response {
+    let methods = if request.target == "*" {
+        server.router.methods()
+    } else {
+        server.router.allowed_methods(&request.path)
+    };
+    if methods.is_empty() {
+        return Response::not_found();
+    }
+    Response::ok().header("Allow", &allow_header(methods))
+}
+
+fn allow_header(mut methods: Vec<HttpMethod>) -> String {
+    methods.push(HttpMethod::Options);
+    let methods: Vec<&str> = methods.iter().map(|m| m.as_str()).collect();
+    methods.join(", ")
+}
+
+// Labels the response with an X-Request-Id from `id` unless it has one
+// already, as a parsed request's response does by the time it's sent.
+fn with_request_id(response: Response, id: impl FnOnce() -> String) -> Response {
+    match response.header_value("X-Request-Id") {
+        Some(_) => response,
+        None => response.header("X-Request-Id", &id()),
+    }
+}
+
+// Swaps the body for the `--error-page` (or `--not-found-file`) file, keeping
+// the headers that still apply (Allow, WWW-Authenticate, ...). If the file
+// can't be read the built-in response goes out instead.
+fn with_error_page(mut response: Response, page: &Path) -> Response {
+    match fs::read(page) {
+        Ok(html) => {
+            response.headers.retain(|(name, _)| {
+                !["Content-Type", "Content-Encoding", "Content-Length"]
+                    .iter()
+                    .any(|replaced| name.eq_ignore_ascii_case(replaced))
+            });
+            response.header("Content-Type", "text/html; charset=utf-8").body(html)
+        }
+        Err(e) => {
+            eprintln!("Failed to read error page {}: {}", page.display(), e);
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, exchange, request, Wire};
+
+    fn server() -> Server {
+        Server::new(testing::config(&["--quiet", "--access-log", "/dev/null"]), Router::new(), Vec::new()).unwrap()
+    }
+
+    // stands in for a route with a bug in it
+    fn panicking(_: &Request, _: &str, _: &Server) -> Result<Response, std::io::Error> {
+        panic!("deliberate panic for the test");
+    }
+
+    #[test]
+    fn a_panicking_handler_gets_500_and_the_connection_closed() {
+        let server = server();
+        let mut wire = Wire::new(b"GET /boom HTTP/1.1\r\nHost: x\r\n\r\nGET /next HTTP/1.1\r\nHost: x\r\n\r\n");
+        handle_client(&mut wire, "127.0.0.1", &server, panicking).unwrap();
+        let output = String::from_utf8_lossy(&wire.output);
+        assert!(output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", output);
+        assert!(output.contains("Connection: close\r\n"));
+        // the pipelined request behind it isn't answered
+        assert_eq!(output.matches("HTTP/1.1 ").count(), 1);
+    }
+
+    #[test]
+    fn a_panic_outside_the_handler_costs_only_its_connection() {
+        let result = isolate_panics(|| panic!("deliberate panic for the test"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn head_gets_the_get_headers_without_the_body() {
+        let output = exchange(&[], &request("HEAD", "/echo/abc", &[], b""));
+        let text = String::from_utf8_lossy(&output);
+        let (head, body) = text.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\nContent-Length: 3\r\n"), "{}", head);
+        assert!(head.contains("\r\nContent-Type: text/plain\r\n"));
+        assert_eq!(body, "");
+    }
+
+    #[test]
+    fn an_oversized_body_gets_413() {
+        let declared = exchange(&["--max-body-size", "16"], &request("POST", "/echo/abc", &[], &[b'x'; 17]));
+        assert!(declared.starts_with(b"HTTP/1.1 413 Payload Too Large\r\n"), "{}", String::from_utf8_lossy(&declared));
+        let chunked = request("POST", "/echo/abc", &[("Transfer-Encoding", "chunked")], b"")
+            .into_iter()
+            .chain(b"10\r\n0123456789abcdef\r\n1\r\nx\r\n0\r\n\r\n".iter().copied())
+            .collect::<Vec<u8>>();
+        let output = exchange(&["--max-body-size", "16"], &chunked);
+        assert!(output.starts_with(b"HTTP/1.1 413 Payload Too Large\r\n"), "{}", String::from_utf8_lossy(&output));
+        let fits = exchange(&["--max-body-size", "16"], &request("POST", "/echo/abc", &[], &[b'x'; 16]));
+        assert!(!fits.starts_with(b"HTTP/1.1 413"));
+    }
+
+    #[test]
+    fn headers_over_the_cap_get_431_and_the_connection_closed() {
+        let padding = "a".repeat(300);
+        let args = ["--max-header-bytes", "256"];
+        let output = exchange(&args, &request("GET", "/", &[("X-Padding", &padding)], b""));
+        let text = String::from_utf8_lossy(&output);
+        assert!(text.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"), "{}", text);
+        assert!(text.contains("Connection: close\r\n"));
+        // one that never ends is refused just the same
+        let endless = format!("GET / HTTP/1.1\r\nHost: x\r\nX-Padding: {}", padding);
+        assert!(exchange(&args, endless.as_bytes()).starts_with(b"HTTP/1.1 431 "));
+        assert!(exchange(&args, &request("GET", "/", &[], b"")).starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let mut input = request("GET", "/echo/one", &[], b"");
+        input.extend(request("POST", "/echo-json", &[("Content-Type", "application/json")], b"{}"));
+        input.extend(request("GET", "/echo/two", &[], b""));
+        let replies = testing::replies(&exchange(&[], &input));
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0].body, b"one");
+        assert_eq!(replies[1].body, b"{}");
+        assert_eq!(replies[2].body, b"two");
+    }
+
+    #[test]
+    fn options_lists_the_methods_of_the_server_or_the_route() {
+        let mut input = request("OPTIONS", "*", &[], b"");
+        input.extend(request("OPTIONS", "/echo/x", &[], b""));
+        input.extend(request("OPTIONS", "/nowhere", &[], b""));
+        let replies = testing::replies(&exchange(&[], &input));
+        assert_eq!(replies[0].status, 200);
+        // PUT and DELETE are only for /files/, which needs a --directory
+        assert_eq!(replies[0].header("Allow"), Some("GET, HEAD, POST, OPTIONS"));
+        assert_eq!(replies[1].status, 200);
+        assert_eq!(replies[1].header("Allow"), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(replies[2].status, 404);
+    }
+
+    #[test]
+    fn http_11_needs_a_host_but_http_10_does_not() {
+        let output = exchange(&[], b"GET /echo/hi HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with(b"HTTP/1.1 400 Bad Request\r\n"), "{}", String::from_utf8_lossy(&output));
+        let output = exchange(&[], b"GET /echo/hi HTTP/1.0\r\n\r\n");
+        assert!(output.starts_with(b"HTTP/1.1 200 OK\r\n"), "{}", String::from_utf8_lossy(&output));
+    }
+
+    #[test]
+    fn a_target_with_control_bytes_gets_400() {
+        for raw in [&b"GET /echo/x?a\x01b HTTP/1.1\r\nHost: x\r\n\r\n"[..], b"GET /echo/x?a\nInjected:1 HTTP/1.1\r\nHost: x\r\n\r\n"] {
+            let output = exchange(&[], raw);
+            assert!(output.starts_with(b"HTTP/1.1 400 Bad Request\r\n"), "{}", String::from_utf8_lossy(&output));
+        }
+    }
+
+    // stands in for a route whose I/O failed
+    fn failing(_: &Request, _: &str, _: &Server) -> Result<Response, std::io::Error> {
+        Err(std::io::Error::other("deliberate failure for the test"))
+    }
+
+    #[test]
+    fn a_failing_handler_gets_the_same_negotiated_500_as_a_panicking_one() {
+        let server = server();
+        let input = b"GET /x HTTP/1.1\r\nHost: x\r\nAccept: application/json\r\n\r\n";
+        let mut bodies = Vec::new();
+        for respond in [failing as Respond, panicking] {
+            let mut wire = Wire::new(input);
+            handle_client(&mut wire, "127.0.0.1", &server, respond).unwrap();
+            let reply = testing::replies(&wire.output).remove(0);
+            assert_eq!(reply.status, 500);
+            assert_eq!(reply.header("Content-Type"), Some("application/json"));
+            bodies.push(reply.body);
+        }
+        assert_eq!(bodies[0], bodies[1]);
+    }
 }
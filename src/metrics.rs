@@ -0,0 +1,78 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+// upper bounds of the request duration histogram's buckets, in seconds
+const DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// Counters behind /metrics, updated as each response goes out.
+#[derive(Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    body_bytes: AtomicU64,
+    // only a handful of codes ever show up, so a locked map is cheap enough
+    statuses: Mutex<BTreeMap<u16, u64>>,
+    // per bucket rather than cumulative; rendering adds them up
+    durations: [AtomicU64; DURATION_BUCKETS.len()],
+    // past the last bucket
+    durations_over: AtomicU64,
+    duration_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record(&self, status: u16, body_length: u64, duration: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.body_bytes.fetch_add(body_length, Ordering::Relaxed);
+        let seconds = duration.as_secs_f64();
+        match DURATION_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            Some(bucket) => self.durations[bucket].fetch_add(1, Ordering::Relaxed),
+            None => self.durations_over.fetch_add(1, Ordering::Relaxed),
+        };
+        self.duration_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        let mut statuses = self.statuses.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *statuses.entry(status).or_insert(0) += 1;
+    }
+
+    // Prometheus text exposition format.
+    pub fn render(&self, active_connections: usize) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP http_requests_total Requests answered.");
+        let _ = writeln!(out, "# TYPE http_requests_total counter");
+        let _ = writeln!(out, "http_requests_total {}", self.requests.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP http_responses_total Responses sent, by status code.");
+        let _ = writeln!(out, "# TYPE http_responses_total counter");
+        let statuses = self.statuses.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (status, count) in statuses.iter() {
+            let _ = writeln!(out, "http_responses_total{{code=\"{}\"}} {}", status, count);
+        }
+
+        let _ = writeln!(out, "# HELP http_response_body_bytes_total Response body bytes sent.");
+        let _ = writeln!(out, "# TYPE http_response_body_bytes_total counter");
+        let _ = writeln!(out, "http_response_body_bytes_total {}", self.body_bytes.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP http_request_duration_seconds Time from a request arriving to its response being sent.");
+        let _ = writeln!(out, "# TYPE http_request_duration_seconds histogram");
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(&self.durations) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "http_request_duration_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative);
+        }
+        cumulative += self.durations_over.load(Ordering::Relaxed);
+        let _ = writeln!(out, "http_request_duration_seconds_bucket{{le=\"+Inf\"}} {}", cumulative);
+        let seconds = self.duration_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "http_request_duration_seconds_sum {}", seconds);
+        let _ = writeln!(out, "http_request_duration_seconds_count {}", cumulative);
+
+        let _ = writeln!(out, "# HELP http_active_connections Connections open or waiting for a worker.");
+        let _ = writeln!(out, "# TYPE http_active_connections gauge");
+        let _ = writeln!(out, "http_active_connections {}", active_connections);
+        out
+    }
+}
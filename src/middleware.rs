@@ -0,0 +1,205 @@
+use std::{io, net::IpAddr};
+
+use crate::{
+    auth::Credentials,
+    compression::with_body,
+    config::Config,
+    log::debug,
+    rate_limit::RateLimiter,
+    request::{HttpMethod, Request},
+    response::{Body, Response},
+    server::Server,
+};
+
+const AUTH_REALM: &str = "http-server";
+
+// A layer around request handling. It can answer the request itself, or call
+// `next.run(request)` to hand it on and then adjust the response on the way out.
+pub type Middleware = Box<dyn Fn(&Request, Next) -> io::Result<Response> + Send + Sync>;
+
+// The rest of the chain after the middleware it was given to.
+pub struct Next<'a> {
+    pub server: &'a Server,
+    // the peer's IP, or "-" when there isn't one
+    pub client: &'a str,
+    chain: &'a [Middleware],
+    endpoint: &'a dyn Fn(&Request) -> io::Result<Response>,
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &Request) -> io::Result<Response> {
+        match self.chain.split_first() {
+            Some((middleware, chain)) => middleware(request, Next { chain, ..self }),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+// Runs `request` through the server's middleware, outermost first, ending at `endpoint`.
+pub fn run(
+    server: &Server,
+    client: &str,
+    request: &Request,
+    endpoint: &dyn Fn(&Request) -> io::Result<Response>,
+) -> io::Result<Response> {
+    Next {
+        server,
+        client,
+        chain: &server.middleware,
+        endpoint,
+    }
+    .run(request)
+}
+
+// The layers every request goes through, in the order they run.
+pub fn stack(config: &Config) -> Vec<Middleware> {
+    let mut stack: Vec<Middleware> = vec![Box::new(logging)];
+    if let Some(rate) = config.rate_limit {
+        let limiter = RateLimiter::new(rate);
+        stack.push(Box::new(move |request, next| rate_limit(&limiter, request, next)));
+    }
+    if let Some(origin) = &config.cors_origin {
+        let origin = origin.clone();
+        stack.push(Box::new(move |request, next| cors(&origin, request, next)));
+    }
+    if let Some(credentials) = &config.auth {
+        let credentials = credentials.clone();
+        let all = config.auth_all;
+        stack.push(Box::new(move |request, next| basic_auth(&credentials, all, request, next)));
+    }
+    stack.push(Box::new(compression));
+    stack
+}
+
+fn logging(request: &Request, next: Next) -> io::Result<Response> {
+    debug!("[{}] {} {} {}", request.id, request.method.as_str(), request.target, request.version);
+    debug!("{:#?}", request.headers);
+    if !request.query.is_empty() {
+        debug!("Query: {:?}", request.query);
+    }
+    next.run(request)
+}
+
+// Clients without an IP (Unix socket peers) aren't limited.
+fn rate_limit(limiter: &RateLimiter, request: &Request, next: Next) -> io::Result<Response> {
+    let Ok(ip) = next.client.parse::<IpAddr>() else {
+        return next.run(request);
+    };
+    match limiter.check(ip) {
+        Ok(()) => next.run(request),
+        Err(wait) => {
+            eprintln!("[{}] Rate limit exceeded by {}", request.id, ip);
+            // Retry-After is whole seconds, so round up rather than invite an early retry
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            Ok(Response::too_many_requests().header("Retry-After", &retry_after.to_string()))
+        }
+    }
+}
+
+// Answers preflights for any route itself, and tells the browser which
+// methods and headers the rest may use. An OPTIONS without
+// Access-Control-Request-Method is an ordinary one and is routed as usual.
+fn cors(origin: &str, request: &Request, next: Next) -> io::Result<Response> {
+    let server = next.server;
+    let preflight = request.method == HttpMethod::Options && request.headers.contains_key("access-control-request-method");
+    let response = if preflight {
+        Response::no_content()
+    } else {
+        next.run(request)?
+    };
+
+    let methods: Vec<&str> = server
+        .router
+        .allowed_methods(&request.path)
+        .iter()
+        .chain(&[HttpMethod::Options])
+        .map(|m| m.as_str())
+        .collect();
+    // echoed back, so anything but a list of header names is ignored
+    let allow_headers = request
+        .headers
+        .get("access-control-request-headers")
+        .filter(|requested| is_token_list(requested))
+        .map_or("Content-Type", |requested| requested.as_str());
+    Ok(response
+        .header("Access-Control-Allow-Origin", origin)
+        .header("Access-Control-Allow-Methods", &methods.join(", "))
+        .header("Access-Control-Allow-Headers", allow_headers))
+}
+
+// Whether `value` is a comma-separated list of RFC 9110 tokens, like the
+// header names a preflight asks about.
+fn is_token_list(value: &str) -> bool {
+    let is_tchar = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+    value.split(',').map(str::trim).all(|name| !name.is_empty() && name.bytes().all(is_tchar))
+}
+
+// Turns away requests for protected routes without the right credentials.
+// Liveness checks stay open so monitoring doesn't need the password.
+fn basic_auth(credentials: &Credentials, all: bool, request: &Request, next: Next) -> io::Result<Response> {
+    let protected = match request.path.as_str() {
+        "/health" | "/healthz" => false,
+        path => all || path.starts_with("/files/"),
+    };
+    if !protected || request.headers.get("authorization").is_some_and(|value| credentials.check(value)) {
+        return next.run(request);
+    }
+    eprintln!("[{}] Unauthorized {} {}", request.id, request.method.as_str(), request.path);
+    Ok(Response::unauthorized().header("WWW-Authenticate", &format!("Basic realm=\"{}\"", AUTH_REALM)))
+}
+
+// Compresses in-memory bodies with the negotiated codec for clients that
+// accept one. Files and streams are left to the handler, which knows whether
+// they're worth compressing.
+fn compression(request: &Request, next: Next) -> io::Result<Response> {
+    let config = &next.server.config;
+    let mut response = next.run(request)?;
+    let already_encoded = response.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"));
+    if already_encoded || response.body.is_empty() {
+        return Ok(response);
+    }
+    match std::mem::replace(&mut response.body, Body::Bytes(Vec::new())) {
+        Body::Bytes(body) => Ok(with_body(response, &body, &request.headers, config)),
+        body => {
+            response.body = body;
+            Ok(response)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{exchange, replies, request};
+
+    #[test]
+    fn is_token_list_takes_header_names_only() {
+        assert!(is_token_list("Content-Type"));
+        assert!(is_token_list("x-one, X-Two,x-three"));
+        assert!(!is_token_list(""));
+        assert!(!is_token_list("a,,b"));
+        assert!(!is_token_list("x-one; evil"));
+        assert!(!is_token_list("x\u{0}y"));
+    }
+
+    #[test]
+    fn a_preflight_echoes_only_a_clean_header_list() {
+        let args = ["--cors-origin", "https://app.example"];
+        let preflight = |requested: &str| {
+            let headers = [("Access-Control-Request-Method", "PUT"), ("Access-Control-Request-Headers", requested)];
+            replies(&exchange(&args, &request("OPTIONS", "/echo/x", &headers, b""))).remove(0)
+        };
+        assert_eq!(preflight("X-Custom, Authorization").header("Access-Control-Allow-Headers"), Some("X-Custom, Authorization"));
+        assert_eq!(preflight("X-Custom\" bad").header("Access-Control-Allow-Headers"), Some("Content-Type"));
+    }
+
+    #[test]
+    fn a_control_character_in_a_header_value_gets_400() {
+        for value in ["a\rSet-Cookie: x=1", "a\nSet-Cookie: x=1", "a\x01b"] {
+            let output = exchange(&[], &request("GET", "/echo/x", &[("X-Thing", value)], b""));
+            assert!(output.starts_with(b"HTTP/1.1 400 Bad Request\r\n"), "{}", String::from_utf8_lossy(&output));
+            // the error body quotes the line with its control characters escaped
+            assert!(!output.windows(11).any(|window| window == b"\nSet-Cookie"));
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::request::{find_header_end, parse_headers};
+
+#[derive(Debug, Error)]
+pub enum MultipartError {
+    #[error("body doesn't start with the boundary")]
+    MissingBoundary,
+    #[error("part {0} has no blank line ending its headers")]
+    UnterminatedHeaders(usize),
+    #[error("body ends before the closing boundary")]
+    Unterminated,
+    #[error("part {0} has a control character in its headers")]
+    InvalidHeaders(usize),
+}
+
+// One part of a `multipart/form-data` body.
+#[derive(Debug)]
+pub struct Part<'a> {
+    // the form field it was sent as
+    pub name: Option<String>,
+    // set for file inputs, as the browser gave it
+    pub filename: Option<String>,
+    pub content: &'a [u8],
+}
+
+// The boundary out of a `multipart/form-data; boundary=...` Content-Type, or
+// None for any other type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let (media_type, params) = content_type.split_once(';')?;
+    if !media_type.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    header_params(params).remove("boundary").filter(|boundary| !boundary.is_empty())
+}
+
+// Splits a multipart body on `boundary`. The preamble before the first
+// boundary and the epilogue after the last are ignored, as RFC 2046 says.
+pub fn parse<'a>(body: &'a [u8], boundary: &str) -> Result<Vec<Part<'a>>, MultipartError> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    // every boundary after the first sits on a line of its own
+    let next_delimiter = [b"\r\n", delimiter.as_slice()].concat();
+    let mut pos = if body.starts_with(&delimiter) {
+        delimiter.len()
+    } else {
+        find(body, &next_delimiter, 0).ok_or(MultipartError::MissingBoundary)? + next_delimiter.len()
+    };
+
+    let mut parts = Vec::new();
+    loop {
+        let rest = &body[pos..];
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        // transport padding may follow a boundary before its line ends
+        let line_end = find(body, b"\r\n", pos).ok_or(MultipartError::Unterminated)?;
+        let part_start = line_end + 2;
+        // a part may have no headers at all
+        let (headers, content_start) = if body[part_start..].starts_with(b"\r\n") {
+            (HashMap::new(), part_start + 2)
+        } else {
+            let header_end = find_header_end(&body[part_start..])
+                .ok_or(MultipartError::UnterminatedHeaders(parts.len() + 1))?;
+            let head = String::from_utf8_lossy(&body[part_start..part_start + header_end]);
+            let headers = parse_headers(head.split("\r\n")).map_err(|_| MultipartError::InvalidHeaders(parts.len() + 1))?;
+            (headers, part_start + header_end)
+        };
+        let content_end = find(body, &next_delimiter, content_start).ok_or(MultipartError::Unterminated)?;
+
+        let disposition = headers
+            .get("content-disposition")
+            .and_then(|value| value.split_once(';'))
+            .map(|(_, params)| header_params(params))
+            .unwrap_or_default();
+        parts.push(Part {
+            name: disposition.get("name").cloned(),
+            filename: disposition.get("filename").cloned(),
+            content: &body[content_start..content_end],
+        });
+        pos = content_end + next_delimiter.len();
+    }
+}
+
+// `key=value` parameters after a header's `;`, with quoted values unquoted.
+// Quoted values may themselves contain `;`, as filenames sometimes do.
+fn header_params(params: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut rest = params;
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim_start_matches([';', ' ', '\t']).trim().to_ascii_lowercase();
+        let value = value.trim_start();
+        let (value, remainder) = match value.strip_prefix('"') {
+            Some(quoted) => unquote(quoted),
+            None => match value.split_once(';') {
+                Some((value, remainder)) => (value.trim().to_string(), remainder),
+                None => (value.trim().to_string(), ""),
+            },
+        };
+        parsed.insert(key, value);
+        rest = remainder;
+    }
+    parsed
+}
+
+// A quoted value up to its closing quote, and whatever follows it. Browsers
+// percent-encode quotes in filenames rather than escaping them, and old ones
+// send Windows paths, so a backslash is just a backslash.
+fn unquote(quoted: &str) -> (String, &str) {
+    match quoted.split_once('"') {
+        Some((value, remainder)) => (value.to_string(), remainder),
+        None => (quoted.to_string(), ""),
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack[from..]
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| from + pos)
+}
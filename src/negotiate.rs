@@ -0,0 +1,48 @@
+// Splits a header like Accept or Accept-Encoding into its lowercased values and
+// their `;q=` quality, 1 when not given. Other parameters are dropped.
+pub fn quality_list(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let value = params.next()?.trim().to_ascii_lowercase();
+            if value.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok())?;
+            Some((value, quality.clamp(0.0, 1.0)))
+        })
+        .collect()
+}
+
+// Which of `offers` the client's Accept header ranks highest, going by the most
+// specific range matching each (`text/html` over `text/*` over `*/*`). Ties go
+// to the earlier offer, so the first is also what a client without Accept gets.
+// None when the client accepts none of them.
+pub fn media_type<'a>(accept: Option<&str>, offers: &[&'a str]) -> Option<&'a str> {
+    let Some(accept) = accept else {
+        return offers.first().copied();
+    };
+    let ranges = quality_list(accept);
+    let mut best: Option<(&str, f32)> = None;
+    for &offer in offers {
+        let kind = offer.split('/').next().unwrap_or("");
+        let quality = ranges
+            .iter()
+            .filter_map(|(range, quality)| match range.as_str() {
+                "*/*" => Some((0, *quality)),
+                range if range.strip_suffix("/*") == Some(kind) => Some((1, *quality)),
+                range if range == offer => Some((2, *quality)),
+                _ => None,
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, quality)| quality);
+        if quality > 0.0 && !best.is_some_and(|(_, best)| quality <= best) {
+            best = Some((offer, quality));
+        }
+    }
+    best.map(|(offer, _)| offer)
+}
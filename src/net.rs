@@ -0,0 +1,266 @@
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use socket2::{Domain, Socket, Type};
+
+use crate::config::Config;
+
+// Owner and group may connect, so a proxy in the same group can reach the socket
+const UNIX_SOCKET_MODE: u32 = 0o660;
+// how often a non-blocking listener with nothing waiting is checked again
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const RESOURCE_BACKOFF: Duration = Duration::from_millis(100);
+
+// Where connections come from: a TCP port, or a Unix socket with `--unix`.
+pub enum Listener {
+    Tcp(TcpListener),
+    // the socket file is removed again when the listener is dropped
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn bind(config: &Config) -> io::Result<Listener> {
+        let Some(path) = &config.unix else {
+            return Ok(Listener::Tcp(bind_tcp(config, config.port)?));
+        };
+        // a socket left behind by an earlier run would make bind fail, but
+        // anything that isn't a socket is left alone
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(UNIX_SOCKET_MODE))?;
+        Ok(Listener::Unix(listener, path.clone()))
+    }
+
+    // A TCP listener on another port of the same host, whatever `--unix` says.
+    pub fn bind_port(config: &Config, port: u16) -> io::Result<Listener> {
+        Ok(Listener::Tcp(bind_tcp(config, port)?))
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => Ok(Connection::Tcp(listener.accept()?.0)),
+            Listener::Unix(listener, _) => Ok(Connection::Unix(listener.accept()?.0)),
+        }
+    }
+}
+
+// How long to wait before accepting again after `e`, or None when the
+// listener itself is broken and retrying won't help.
+pub fn accept_backoff(e: &io::Error) -> Option<Duration> {
+    match e.kind() {
+        // the client gave up while still queued; the next one is fine
+        io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted => {
+            return Some(Duration::ZERO)
+        }
+        io::ErrorKind::WouldBlock => return Some(ACCEPT_POLL_INTERVAL),
+        _ => {}
+    }
+    match e.raw_os_error()? {
+        // out of descriptors or memory: give connections a moment to finish
+        // and free some up, rather than spin on the same error
+        libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM => Some(RESOURCE_BACKOFF),
+        // network errors on the new connection that Linux reports from accept
+        libc::EPROTO | libc::ENOPROTOOPT | libc::ENETDOWN | libc::ENETUNREACH | libc::EHOSTDOWN
+        | libc::EHOSTUNREACH | libc::EOPNOTSUPP | libc::EPERM => Some(Duration::ZERO),
+        _ => None,
+    }
+}
+
+// Like `TcpListener::bind`, trying each address the host resolves to, but
+// with SO_REUSEADDR up to the config, so a restart can take the port back
+// while the last run's connections sit in TIME_WAIT.
+fn bind_tcp(config: &Config, port: u16) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for address in (config.host.as_str(), port).to_socket_addrs()? {
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+        socket.set_reuse_address(config.reuse_addr)?;
+        // `::` takes IPv4 clients too where the platform allows it, the way
+        // `0.0.0.0` and `::` together would
+        if address.is_ipv6() && address.ip().is_unspecified() {
+            let _ = socket.set_only_v6(false);
+        }
+        match socket.bind(&address.into()).and_then(|_| socket.listen(config.backlog)) {
+            Ok(()) => return Ok(socket.into()),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "host resolved to no addresses")))
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// An accepted connection, whatever it runs over; all of them read and write the same way.
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Connection {
+    // None for Unix sockets, whose peers have no address.
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        let tcp = match self {
+            Connection::Tcp(stream) => stream,
+            Connection::Tls(stream) => &stream.sock,
+            Connection::Unix(_) => return None,
+        };
+        // IPv4 clients of a dual-stack listener arrive as ::ffff:a.b.c.d
+        tcp.peer_addr().ok().map(|addr| addr.ip().to_canonical())
+    }
+
+    // The peer's IP for logging, or `-` where there isn't one (Unix sockets).
+    pub fn client(&self) -> String {
+        self.peer_ip().map_or("-".to_string(), |ip| ip.to_string())
+    }
+
+    // Some platforms (the BSDs and macOS, though not Linux) let accepted
+    // sockets inherit the listener's non-blocking mode, so this puts them
+    // back to blocking with the given read timeout.
+    pub fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(timeout))
+            }
+            Connection::Tls(stream) => {
+                stream.sock.set_nonblocking(false)?;
+                stream.sock.set_read_timeout(Some(timeout))
+            }
+            Connection::Unix(stream) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(timeout))
+            }
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Connection::Tls(stream) => stream.sock.set_nonblocking(nonblocking),
+            Connection::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    // A write that can't hand the kernel any bytes for `timeout` fails with
+    // WouldBlock or TimedOut instead of waiting on the client forever.
+    pub fn set_write_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_write_timeout(Some(timeout)),
+            Connection::Tls(stream) => stream.sock.set_write_timeout(Some(timeout)),
+            Connection::Unix(stream) => stream.set_write_timeout(Some(timeout)),
+        }
+    }
+
+    // Turns Nagle's algorithm off (or back on). Unix sockets don't have it.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_nodelay(nodelay),
+            Connection::Tls(stream) => stream.sock.set_nodelay(nodelay),
+            Connection::Unix(_) => Ok(()),
+        }
+    }
+
+    // Wraps a TCP connection in TLS. The handshake itself happens lazily on
+    // the first read. Unix sockets are local, so they stay plain.
+    pub fn start_tls(self, config: &Arc<ServerConfig>) -> io::Result<Connection> {
+        match self {
+            Connection::Tcp(stream) => {
+                let session = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+                Ok(Connection::Tls(Box::new(StreamOwned::new(session, stream))))
+            }
+            other => Ok(other),
+        }
+    }
+
+    // Sends FIN once whatever was written has gone, so the peer reads a
+    // complete response before the socket closes.
+    pub fn shutdown_write(&self) {
+        let _ = match self {
+            Connection::Tcp(stream) => stream.shutdown(Shutdown::Write),
+            Connection::Tls(stream) => stream.sock.shutdown(Shutdown::Write),
+            Connection::Unix(stream) => stream.shutdown(Shutdown::Write),
+        };
+    }
+
+    // Tells a TLS peer the connection is ending on purpose, so it can tell
+    // that apart from a truncation.
+    pub fn close(&mut self) {
+        if let Connection::Tls(stream) = self {
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Unix(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Unix(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// A stream whose reads can be given a deadline, so the keep-alive loop can
+// wait between requests only as long as the idle limit has left.
+pub trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl ReadTimeout for Connection {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        Connection::set_read_timeout(self, timeout)
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    io::{self, Cursor, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    str::FromStr,
+};
+
+use crate::{
+    config::Config,
+    request::{find_header_end, HttpMethod, Request},
+    response::Response,
+};
+
+// Connection-level headers that describe one hop, not the message, so they
+// aren't passed through in either direction.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// Where `--proxy` sends requests: an `http://host:port/path` URL.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    host: String,
+    port: u16,
+    // prepended to the rest of the client's path; empty for the root
+    path: String,
+}
+
+impl FromStr for Upstream {
+    type Err = ();
+
+    fn from_str(url: &str) -> Result<Upstream, ()> {
+        let rest = url.strip_prefix("http://").ok_or(())?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().map_err(|_| ())?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(());
+        }
+        Ok(Upstream {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+// Sends `request` on to the upstream, with `rest` (what followed the proxied
+// prefix) appended to its path, and relays the answer. The upstream is asked
+// for HTTP/1.0 and told to close, so its body simply runs to the end of the
+// connection and never comes chunked.
+pub fn forward(request: &Request, rest: &str, upstream: &Upstream, config: &Config) -> Response {
+    let query = request.target.find('?').map_or("", |start| &request.target[start..]);
+    let target = format!("{}/{}{}", upstream.path, rest, query);
+    let mut stream = match connect(upstream, config) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("[{}] Failed to connect to upstream {}:{}: {}", request.id, upstream.host, upstream.port, e);
+            return Response::new(502, "Bad Gateway");
+        }
+    };
+
+    let mut head = format!("{} {} HTTP/1.0\r\n", request.method.as_str(), target);
+    for (name, value) in &request.headers {
+        // Expect was already dealt with on our side of the hop, and the
+        // request's ID is the one this server logs it under
+        let replaced = ["host", "content-length", "expect", "x-request-id"];
+        if !HOP_BY_HOP.contains(&name.as_str()) && !replaced.contains(&name.as_str()) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str(&format!("Host: {}:{}\r\n", upstream.host, upstream.port));
+    if !request.id.is_empty() {
+        head.push_str(&format!("X-Request-Id: {}\r\n", request.id));
+    }
+    head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", request.body.len()));
+    if let Err(e) = stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(&request.body)) {
+        eprintln!("[{}] Failed to send request upstream: {}", request.id, e);
+        return Response::new(502, "Bad Gateway");
+    }
+
+    match relay(stream, request, config) {
+        Ok(Some(response)) => response,
+        Ok(None) => {
+            eprintln!("[{}] Upstream sent a malformed response", request.id);
+            Response::new(502, "Bad Gateway")
+        }
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            eprintln!("[{}] Upstream timed out", request.id);
+            Response::new(504, "Gateway Timeout")
+        }
+        Err(e) => {
+            eprintln!("[{}] Failed to read upstream response: {}", request.id, e);
+            Response::new(502, "Bad Gateway")
+        }
+    }
+}
+
+fn connect(upstream: &Upstream, config: &Config) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in (upstream.host.as_str(), upstream.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, config.read_timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(config.read_timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "host resolved to no addresses")))
+}
+
+// Reads the upstream's status and headers, and turns the rest of the
+// connection into the response body. None if the head doesn't parse.
+fn relay(mut stream: TcpStream, request: &Request, config: &Config) -> io::Result<Option<Response>> {
+    let mut buffer = Vec::new();
+    let mut buf = [0; 8192];
+    let header_end = loop {
+        if let Some(end) = find_header_end(&buffer) {
+            break end;
+        }
+        if buffer.len() > config.max_header_bytes {
+            return Ok(None);
+        }
+        match stream.read(&mut buf)? {
+            0 => return Ok(None),
+            n => buffer.extend_from_slice(&buf[..n]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut status_line = lines.next().unwrap_or("").splitn(3, ' ');
+    let (Some(version), Some(status)) = (status_line.next(), status_line.next()) else {
+        return Ok(None);
+    };
+    let Ok(status) = status.parse::<u16>() else {
+        return Ok(None);
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Ok(None);
+    }
+    let mut response = Response::new(status, status_line.next().unwrap_or(""));
+
+    let mut content_length = None;
+    for line in lines.filter(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (name, value) = (name.trim(), value.trim());
+        let lowercase = name.to_ascii_lowercase();
+        if lowercase == "content-length" {
+            content_length = value.parse::<u64>().ok();
+        // our own Server header and request ID go out instead, as they do
+        // for every response, so the ID matches the one in our log lines
+        } else if !HOP_BY_HOP.contains(&lowercase.as_str()) && lowercase != "server" && lowercase != "x-request-id" {
+            response = response.header(name, value);
+        }
+    }
+
+    // these never have a body, whatever the headers say
+    if request.method == HttpMethod::Head || status / 100 == 1 || status == 204 || status == 304 {
+        if let Some(length) = content_length.filter(|_| request.method == HttpMethod::Head) {
+            response = response.header("Content-Length", &length.to_string());
+        }
+        return Ok(Some(response));
+    }
+
+    let leftover = Cursor::new(buffer.split_off(header_end));
+    // a length the upstream gave is passed on, to clients of either version
+    if let Some(length) = content_length {
+        return Ok(Some(response.reader(leftover.chain(stream), length)));
+    }
+    let body = leftover.chain(stream);
+    // a body that runs to the end of the connection goes out chunked, but
+    // only HTTP/1.1 clients can take that; older ones get it whole
+    if request.version == "HTTP/1.1" {
+        return Ok(Some(response.stream(body)));
+    }
+    let mut whole = Vec::new();
+    body.take(config.max_body_size as u64 + 1).read_to_end(&mut whole)?;
+    if whole.len() > config.max_body_size {
+        eprintln!("[{}] Upstream response too large to buffer for an HTTP/1.0 client", request.id);
+        return Ok(Some(Response::new(502, "Bad Gateway")));
+    }
+    Ok(Some(response.body(whole)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    use crate::testing::{exchange, replies, request};
+
+    // An upstream that answers one connection with `response` and hangs up,
+    // handing back the request it got.
+    fn upstream(response: &'static [u8]) -> (String, mpsc::Receiver<Vec<u8>>) {
+        let (seen, requests) = mpsc::channel();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            stream.write_all(response).unwrap();
+            let _ = seen.send(request);
+        });
+        (format!("/api=http://127.0.0.1:{}", port), requests)
+    }
+
+    #[test]
+    fn a_known_length_is_kept_and_an_unknown_one_is_chunked() {
+        let (proxy, _) = upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello");
+        let reply = replies(&exchange(&["--proxy", &proxy], &request("GET", "/api/x", &[], b""))).remove(0);
+        assert_eq!(reply.header("Content-Length"), Some("5"));
+        assert_eq!(reply.header("Transfer-Encoding"), None);
+        assert_eq!(reply.body, b"hello");
+
+        let (proxy, _) = upstream(b"HTTP/1.0 200 OK\r\n\r\nuntil the end");
+        let reply = replies(&exchange(&["--proxy", &proxy], &request("GET", "/api/x", &[], b""))).remove(0);
+        assert_eq!(reply.header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(reply.body, b"until the end");
+    }
+
+    #[test]
+    fn the_request_id_replaces_the_upstreams_both_ways() {
+        let (proxy, requests) = upstream(b"HTTP/1.1 200 OK\r\nX-Request-Id: theirs\r\nContent-Length: 0\r\n\r\n");
+        let headers = [("X-Request-Id", "ours-123")];
+        let reply = replies(&exchange(&["--proxy", &proxy], &request("GET", "/api/x", &headers, b""))).remove(0);
+        assert_eq!(reply.header("X-Request-Id"), Some("ours-123"));
+        assert_eq!(reply.headers.iter().filter(|(name, _)| name == "X-Request-Id").count(), 1);
+        let sent = String::from_utf8(requests.recv().unwrap()).unwrap();
+        assert_eq!(sent.matches("X-Request-Id: ours-123\r\n").count(), 1, "{}", sent);
+        assert!(!sent.contains("x-request-id"));
+    }
+}
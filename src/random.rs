@@ -0,0 +1,15 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+// 16 random bytes in hex: unguessable, and unique enough to tell requests or
+// multipart boundaries apart.
+pub fn hex_token() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut bytes = [0; 16];
+    // still unique within this process should the system's randomness fail
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        bytes[8..].copy_from_slice(&NEXT.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    }
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
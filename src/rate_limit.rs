@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// How often buckets that have filled back up are dropped, so one-off
+// clients don't pile up in the map forever.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// A token bucket per client IP. Each allows a burst of `rate` requests and
+// refills at `rate` tokens a second.
+pub struct RateLimiter {
+    rate: f64,
+    state: Mutex<State>,
+}
+
+struct State {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_prune: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> RateLimiter {
+        RateLimiter {
+            rate: rate as f64,
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+
+    // Takes a token for `ip`, or says how long until one is available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if now.duration_since(state.last_prune) >= PRUNE_INTERVAL {
+            let rate = self.rate;
+            state.buckets.retain(|_, bucket| bucket.refilled(now, rate) < rate);
+            state.last_prune = now;
+        }
+
+        let bucket = state.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.rate,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(now, self.rate);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, rate: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate).min(rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{exchange, replies, request};
+
+    #[test]
+    fn a_burst_past_the_rate_is_refused_per_ip() {
+        let limiter = RateLimiter::new(3);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..3 {
+            assert!(limiter.check(ip).is_ok());
+        }
+        let wait = limiter.check(ip).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1), "{:?}", wait);
+        // another client has a bucket of its own
+        assert!(limiter.check("192.0.2.2".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn requests_past_the_rate_get_429_with_retry_after() {
+        let input: Vec<u8> = (0..4).flat_map(|_| request("GET", "/echo/hi", &[], b"")).collect();
+        let replies = replies(&exchange(&["--rate-limit", "2"], &input));
+        let statuses: Vec<u16> = replies.iter().map(|reply| reply.status).collect();
+        assert_eq!(statuses, [200, 200, 429, 429]);
+        assert!(replies[2].header("Retry-After").is_some());
+    }
+}
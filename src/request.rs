@@ -0,0 +1,817 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::{Read, Write},
+};
+use thiserror::Error;
+
+use crate::config::Config;
+
+// How much room each read of a request gets.
+const READ_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpMethod {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Head,
+    Options,
+    Patch,
+}
+
+impl HttpMethod {
+    // Method names are case-sensitive, so "get" is not a GET
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(method: &str) -> Option<HttpMethod> {
+        match method {
+            "GET" => Some(HttpMethod::Get),
+            "POST" => Some(HttpMethod::Post),
+            "PUT" => Some(HttpMethod::Put),
+            "DELETE" => Some(HttpMethod::Delete),
+            "HEAD" => Some(HttpMethod::Head),
+            "OPTIONS" => Some(HttpMethod::Options),
+            "PATCH" => Some(HttpMethod::Patch),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Delete => "DELETE",
+            HttpMethod::Head => "HEAD",
+            HttpMethod::Options => "OPTIONS",
+            HttpMethod::Patch => "PATCH",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("request has no blank line ending its headers")]
+    Incomplete,
+    #[error("malformed request line: {0:?}")]
+    MalformedRequestLine(String),
+    #[error("unknown method: {0}")]
+    UnknownMethod(String),
+    #[error("unsupported HTTP version: {0:?}")]
+    UnsupportedVersion(String),
+    #[error("invalid request target: {0:?}")]
+    InvalidTarget(String),
+    #[error("HTTP/1.1 requests need a Host header")]
+    MissingHost,
+    #[error("header line {0:?} has a control character in it")]
+    InvalidHeader(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ReadError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("declared body of {0} bytes is over the size limit")]
+    BodyTooLarge(usize),
+    #[error("request line is over {0} bytes")]
+    RequestLineTooLong(usize),
+    #[error("header section is over {0} bytes")]
+    HeadersTooLarge(usize),
+    #[error("more than {0} header lines")]
+    TooManyHeaders(usize),
+    // the body's length can't be worked out safely
+    #[error("{0}")]
+    InvalidFraming(String),
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: HttpMethod,
+    // always origin-form (or `*`); an absolute-form target's authority has
+    // been moved into the Host header
+    pub target: String,
+    // `target` without its query string; this is what gets routed on
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub version: String,
+    // names are lowercased, since header names are case-insensitive
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    // the X-Request-Id its response goes out with, for its log lines; empty
+    // until the keep-alive loop assigns it
+    pub id: String,
+}
+
+impl Request {
+    pub fn parse(raw: &[u8]) -> Result<Request, ParseError> {
+        let header_end = find_header_end(raw).ok_or(ParseError::Incomplete)?;
+        let head = String::from_utf8_lossy(&raw[..header_end]);
+        let mut lines = head.split("\r\n");
+
+        let request_line = lines.next().unwrap_or("");
+        let parts: Vec<&str> = request_line.splitn(3, ' ').collect();
+        if parts.len() != 3 {
+            return Err(ParseError::MalformedRequestLine(request_line.to_string()));
+        }
+        let method = HttpMethod::from_str(parts[0])
+            .ok_or_else(|| ParseError::UnknownMethod(parts[0].to_string()))?;
+
+        // HTTP/2 and later don't speak this wire format at all
+        let version = parts[2];
+        if version != "HTTP/1.0" && version != "HTTP/1.1" {
+            return Err(ParseError::UnsupportedVersion(version.to_string()));
+        }
+
+        // origin-form is the usual case; `*` only means something to OPTIONS,
+        // and absolute-form is what clients send through a proxy. That one is
+        // split into the authority, which stands in for Host as RFC 9112
+        // section 3.2.2 says, and an origin-form target to route on.
+        let raw_target = parts[1];
+        // a control byte, say a bare LF, would forge a line wherever the
+        // target is copied: the access log, a proxied request, a Location
+        if !raw_target.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(ParseError::InvalidTarget(raw_target.to_string()));
+        }
+        let (authority, target) = match absolute_form(raw_target) {
+            Some(("", _)) => {
+                return Err(ParseError::InvalidTarget(raw_target.to_string()))
+            }
+            Some((authority, "")) => (Some(authority), "/".to_string()),
+            Some((authority, rest)) if rest.starts_with('?') => (Some(authority), format!("/{}", rest)),
+            Some((authority, rest)) => (Some(authority), rest.to_string()),
+            None => (None, raw_target.to_string()),
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, parse_query(query)),
+            None => (target.as_str(), HashMap::new()),
+        };
+        let path = if path.starts_with('/') {
+            normalize_path(path).ok_or_else(|| ParseError::InvalidTarget(raw_target.to_string()))?
+        } else if target == "*" && method == HttpMethod::Options {
+            path.to_string()
+        } else {
+            return Err(ParseError::InvalidTarget(raw_target.to_string()));
+        };
+
+        // HTTP/1.0 predates Host, but HTTP/1.1 requires it (RFC 9112 section 3.2)
+        let mut headers = parse_headers(lines)?;
+        if let Some(authority) = authority {
+            headers.insert("host".to_string(), authority.to_string());
+        }
+        if version == "HTTP/1.1" && !headers.contains_key("host") {
+            return Err(ParseError::MissingHost);
+        }
+
+        Ok(Request {
+            method,
+            target,
+            path,
+            query,
+            version: version.to_string(),
+            headers,
+            body: raw[header_end..].to_vec(),
+            id: String::new(),
+        })
+    }
+
+    // The Host header's name or address without any `:port`, and without the
+    // brackets around an IPv6 address.
+    pub fn host(&self) -> Option<&str> {
+        let host = self.headers.get("host")?.as_str();
+        Some(match host.strip_prefix('[') {
+            Some(bracketed) => bracketed.split_once(']').map_or(host, |(address, _)| address),
+            None => host.split_once(':').map_or(host, |(name, _)| name),
+        })
+    }
+
+    // The body's fields when it's an HTML form (`application/x-www-form-urlencoded`),
+    // None for any other content type.
+    pub fn form(&self) -> Option<HashMap<String, String>> {
+        let content_type = self.headers.get("content-type")?;
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        media_type
+            .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+            .then(|| parse_form(&self.body))
+    }
+
+    // HTTP/1.1 connections stay open unless the client asks otherwise, while
+    // HTTP/1.0 ones close unless the client explicitly asks for keep-alive.
+    pub fn wants_close(&self) -> bool {
+        let connection = self.headers.get("connection").map(|value| value.to_ascii_lowercase());
+        let has_token = |token: &str| {
+            connection
+                .as_deref()
+                .is_some_and(|value| value.split(',').any(|t| t.trim() == token))
+        };
+
+        if has_token("close") {
+            true
+        } else if has_token("keep-alive") {
+            false
+        } else {
+            self.version != "HTTP/1.1"
+        }
+    }
+}
+
+// Collapses repeated slashes and `.` segments, and resolves `..` against the
+// segment before it, so routing and the file handlers see one spelling of each
+// path. A trailing slash is kept, since it's what marks a directory. None when
+// `..` would climb above the root, or the path has control characters in it.
+fn normalize_path(path: &str) -> Option<String> {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    let last = path.rsplit('/').next().unwrap_or("");
+    if !segments.is_empty() && matches!(last, "" | "." | "..") {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+// The authority and the rest of a `scheme://authority/path?query` target,
+// with any `user@` dropped from the authority. None for any other form. The
+// scheme is checked against RFC 3986 section 3.1 but otherwise ignored.
+fn absolute_form(target: &str) -> Option<(&str, &str)> {
+    let (scheme, rest) = target.split_once("://")?;
+    let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    if !valid_scheme {
+        return None;
+    }
+    let (authority, rest) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    Some((authority, rest))
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key).into_owned(), percent_decode(value).into_owned())
+        })
+        .collect()
+}
+
+// Percent-decodes the `key=value` pairs of a form body. A key with no `=` gets
+// an empty value, and a key given more than once (say, several checkboxes)
+// keeps every value, comma-separated, in the order they came.
+pub fn parse_form(body: &[u8]) -> HashMap<String, String> {
+    let mut form: HashMap<String, String> = HashMap::new();
+    for pair in String::from_utf8_lossy(body).split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(value);
+        form.entry(percent_decode(key).into_owned())
+            .and_modify(|existing| {
+                existing.push(',');
+                existing.push_str(&value);
+            })
+            .or_insert_with(|| value.into_owned());
+    }
+    form
+}
+
+// Repeated headers are combined into one comma-separated value, as RFC 7230
+// allows, and obsolete folded continuation lines (starting with a space or tab)
+// are joined onto the header they continue.
+pub fn parse_headers<'a, I: Iterator<Item = &'a str>>(lines: I) -> Result<HashMap<String, String>, ParseError> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut last: Option<String> = None;
+    for line in lines {
+        // a bare CR or LF in a value would split it into two lines for
+        // whoever it's echoed or forwarded to
+        if line.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+            return Err(ParseError::InvalidHeader(line.to_string()));
+        }
+        if line.starts_with([' ', '\t']) {
+            match last.as_ref().and_then(|name| headers.get_mut(name)) {
+                Some(value) => {
+                    let continuation = line.trim();
+                    if !continuation.is_empty() {
+                        value.push(' ');
+                        value.push_str(continuation);
+                    }
+                }
+                None => eprintln!("Folded header line with nothing to continue: {}", line),
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            let name = key.trim().to_ascii_lowercase();
+            let value = value.trim(); // Trim whitespace
+            headers
+                .entry(name.clone())
+                .and_modify(|existing| match (existing.is_empty(), value.is_empty()) {
+                    (_, true) => {}
+                    (true, false) => *existing = value.to_string(),
+                    (false, false) => {
+                        existing.push_str(", ");
+                        existing.push_str(value);
+                    }
+                })
+                .or_insert_with(|| value.to_string());
+            last = Some(name);
+        } else if !line.is_empty() { // Ignore empty lines but log others
+            eprintln!("Malformed header encountered: {}", line);
+            last = None;
+        }
+    }
+    Ok(headers)
+}
+
+// Decodes a query or form string: `%XX` escapes, and `+` as a space. A `%`
+// that isn't followed by two hex digits is kept as a literal `%`.
+pub fn percent_decode(s: &str) -> Cow<'_, str> {
+    decode(s, true)
+}
+
+// Decodes the `%XX` escapes in a URL path, where `+` is just a `+`.
+pub fn path_decode(s: &str) -> Cow<'_, str> {
+    decode(s, false)
+}
+
+fn decode(s: &str, plus_is_space: bool) -> Cow<'_, str> {
+    if !(s.contains('%') || plus_is_space && s.contains('+')) {
+        return Cow::Borrowed(s);
+    }
+
+    let hex_value = |b: &u8| (*b as char).to_digit(16).map(|d| d as u8);
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match (bytes.get(i + 1).and_then(hex_value), bytes.get(i + 2).and_then(hex_value)) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    i += 3;
+                }
+                _ => {
+                    decoded.push(b'%');
+                    i += 1;
+                }
+            },
+            b'+' if plus_is_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+pub fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|window| window == b"\r\n\r\n").map(|pos| pos + 4)
+}
+
+// Reads one full request off the stream: everything up to the blank line that
+// ends the header block, then exactly `Content-Length` bytes of body, or a
+// chunked body decoded into a plain one.
+// Bytes are collected in `request`, which the caller owns, so a read that times
+// out part-way through can be retried without losing what already arrived, and
+// one buffer serves every request on a connection. The request ends up in the
+// first however-many bytes of it this returns; the caller drains those once
+// it's done with them, leaving any pipelined requests behind.
+// A request line longer than `max_request_line_bytes`, or a header section
+// longer than `max_header_bytes`, is refused as soon as that many bytes have
+// arrived without its end, a header section with more than `max_headers`
+// lines once it is complete, and a body declared larger than `max_body_size`
+// before any of it is read.
+// Clients sending `Expect: 100-continue` hold the body back until they hear
+// `100 Continue`, which goes out on `stream` once the headers are in, unless
+// `continue_sent` says an earlier call for this request already sent it.
+pub fn read_request<S: Read + Write>(
+    stream: &mut S,
+    request: &mut Vec<u8>,
+    config: &Config,
+    continue_sent: &mut bool,
+) -> Result<usize, ReadError> {
+    let header_end = loop {
+        let line_end = request.windows(2).position(|w| w == b"\r\n");
+        if line_end.unwrap_or(request.len()) > config.max_request_line_bytes {
+            return Err(ReadError::RequestLineTooLong(config.max_request_line_bytes));
+        }
+        match find_header_end(request) {
+            Some(end) if end > config.max_header_bytes => {
+                return Err(ReadError::HeadersTooLarge(config.max_header_bytes))
+            }
+            Some(end) => break end,
+            None if request.len() > config.max_header_bytes => {
+                return Err(ReadError::HeadersTooLarge(config.max_header_bytes))
+            }
+            None => {}
+        }
+        fill(stream, request)?;
+    };
+
+    let head = String::from_utf8_lossy(&request[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let is_http_11 = lines.next().is_some_and(|line| line.ends_with(" HTTP/1.1"));
+    // counted before parsing so thousands of tiny headers never reach the map
+    if lines.clone().filter(|line| !line.is_empty()).count() > config.max_headers {
+        return Err(ReadError::TooManyHeaders(config.max_headers));
+    }
+    let headers = parse_headers(lines)?;
+
+    let chunked = match headers.get("transfer-encoding") {
+        Some(coding) if coding.eq_ignore_ascii_case("chunked") => true,
+        Some(coding) => return Err(ReadError::InvalidFraming(format!("unsupported Transfer-Encoding {:?}", coding))),
+        None => false,
+    };
+    // a message with both could be framed two ways, which is how requests get smuggled
+    if chunked && headers.contains_key("content-length") {
+        return Err(ReadError::InvalidFraming("both Transfer-Encoding and Content-Length given".to_string()));
+    }
+    let content_length = content_length(headers.get("content-length"))?;
+    // refusing outright beats inviting a body we'd throw away
+    if content_length > config.max_body_size {
+        return Err(ReadError::BodyTooLarge(content_length));
+    }
+
+    let mut scan = ChunkScan::default();
+    let body_pending = if chunked {
+        scan_chunked(&request[header_end..], config.max_body_size, &mut scan)?.is_none()
+    } else {
+        request.len() < header_end + content_length
+    };
+    let expects_continue = headers
+        .get("expect")
+        .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"));
+    // HTTP/1.0 clients can't know what to do with a 1xx, so they get none
+    if expects_continue && is_http_11 && !*continue_sent && body_pending {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        *continue_sent = true;
+    }
+
+    if chunked {
+        let body_end = loop {
+            if let Some(body_end) = scan_chunked(&request[header_end..], config.max_body_size, &mut scan)? {
+                break body_end;
+            }
+            fill(stream, request)?;
+        };
+        let body = &request[header_end..];
+        let length = scan.total;
+
+        // Hand on the request as if it had been sent with a Content-Length, the
+        // way RFC 7230 describes decoding it, so nothing else has to care
+        let mut decoded: Vec<u8> = Vec::with_capacity(header_end + length);
+        for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+            let name = line.split_once(':').map_or("", |(name, _)| name.trim());
+            if !name.eq_ignore_ascii_case("transfer-encoding") {
+                decoded.extend_from_slice(line.as_bytes());
+                decoded.extend_from_slice(b"\r\n");
+            }
+        }
+        decoded.extend_from_slice(format!("Content-Length: {}\r\n\r\n", length).as_bytes());
+        for chunk in scan.chunks {
+            decoded.extend_from_slice(&body[chunk]);
+        }
+        let decoded_length = decoded.len();
+        request.splice(..header_end + body_end, decoded);
+        return Ok(decoded_length);
+    }
+
+    while request.len() < header_end + content_length {
+        fill(stream, request)?;
+    }
+    Ok(header_end + content_length)
+}
+
+// The body length a request declares, 0 when it gives none; a malformed one
+// is an error rather than a guess. Repeated
+// Content-Length headers reach here comma-joined, which is only safe when
+// every copy agrees; otherwise the body could be framed more than one way.
+fn content_length(value: Option<&String>) -> Result<usize, ReadError> {
+    let Some(value) = value else {
+        return Ok(0);
+    };
+    let mut values = value.split(',').map(str::trim);
+    let first = values.next().unwrap_or("");
+    if values.any(|other| other != first) {
+        return Err(ReadError::InvalidFraming(format!("conflicting Content-Length values {:?}", value)));
+    }
+    // digits only: a length we can't read leaves no safe place to end the body
+    match first.parse::<usize>() {
+        Ok(length) if first.bytes().all(|b| b.is_ascii_digit()) => Ok(length),
+        _ => Err(ReadError::InvalidFraming(format!("invalid Content-Length {:?}", value))),
+    }
+}
+
+// Blocks until the next request has started arriving, unless some of it is
+// already in `request`, so whoever is timing requests can leave out the wait
+// between them.
+pub fn wait_for_request<R: Read>(reader: &mut R, request: &mut Vec<u8>) -> Result<(), ReadError> {
+    if request.is_empty() {
+        fill(reader, request)?;
+    }
+    Ok(())
+}
+
+// One read's worth onto the end of `request`, read straight into its spare
+// room, so the buffer only grows when a request is bigger than any before it.
+fn fill<R: Read>(reader: &mut R, request: &mut Vec<u8>) -> Result<(), ReadError> {
+    let filled = request.len();
+    request.resize(filled + READ_SIZE, 0);
+    let result = reader.read(&mut request[filled..]);
+    request.truncate(filled + *result.as_ref().unwrap_or(&0));
+    match result? {
+        // handle_client says whether this cut a request short
+        0 => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Disconnected").into()),
+        _ => Ok(()),
+    }
+}
+
+// How far `scan_chunked` has got through a chunked body, kept between reads
+// so each one only looks at the bytes that are new.
+#[derive(Debug, Default)]
+struct ChunkScan {
+    // where each complete chunk's data sits in the body
+    chunks: Vec<std::ops::Range<usize>>,
+    total: usize,
+    // start of the first chunk, or trailer line, that hasn't all arrived
+    pos: usize,
+    // past the zero-size chunk, reading trailers
+    in_trailers: bool,
+    // how far the search for the CRLF ending the line at `pos` has got
+    searched: usize,
+}
+
+// Walks a chunked body from where `scan` left off, returning where the body
+// ends once the terminating zero-size chunk (and any trailers) have all
+// arrived, or None while more is still to come. The chunks found so far are
+// in `scan`. Chunk extensions and trailers are skipped.
+fn scan_chunked(body: &[u8], max_body_size: usize, scan: &mut ChunkScan) -> Result<Option<usize>, ReadError> {
+    loop {
+        // a CRLF may straddle the end of the last search
+        let from = scan.searched.saturating_sub(1).max(scan.pos);
+        let Some(line_end) = body[from..].windows(2).position(|w| w == b"\r\n").map(|pos| from + pos) else {
+            scan.searched = body.len();
+            return Ok(None);
+        };
+        let line_start = scan.pos;
+
+        if scan.in_trailers {
+            // trailer lines, if any, then the blank line that ends the body
+            scan.pos = line_end + 2;
+            scan.searched = scan.pos;
+            if line_end == line_start {
+                return Ok(Some(scan.pos));
+            }
+            continue;
+        }
+
+        let line = String::from_utf8_lossy(&body[line_start..line_end]);
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| ReadError::InvalidFraming(format!("invalid chunk size {:?}", size)))?;
+        let data_start = line_end + 2;
+
+        if size == 0 {
+            scan.in_trailers = true;
+            scan.pos = data_start;
+            scan.searched = scan.pos;
+            continue;
+        }
+
+        // checked before adding, since a hostile size could overflow the sum
+        if size > max_body_size - scan.total {
+            return Err(ReadError::BodyTooLarge(scan.total.saturating_add(size)));
+        }
+        let data_end = data_start.checked_add(size).filter(|end| end.checked_add(2).is_some());
+        let Some(data_end) = data_end else {
+            return Err(ReadError::InvalidFraming(format!("chunk size {:#x} is too large", size)));
+        };
+        // the size line is read again next time, but the data isn't searched
+        if body.len() < data_end + 2 {
+            return Ok(None);
+        }
+        if &body[data_end..data_end + 2] != b"\r\n" {
+            return Err(ReadError::InvalidFraming("chunk data not followed by CRLF".to_string()));
+        }
+        scan.chunks.push(data_start..data_end);
+        scan.total += size;
+        scan.pos = data_end + 2;
+        scan.searched = scan.pos;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Wire};
+
+    fn config() -> Config {
+        testing::config(&[])
+    }
+
+    // A whole body scanned in one go, finished at `pos`.
+    fn scan_all(body: &[u8], max_body_size: usize) -> Result<Option<ChunkScan>, ReadError> {
+        let mut scan = ChunkScan::default();
+        Ok(scan_chunked(body, max_body_size, &mut scan)?.map(|_| scan))
+    }
+
+    #[test]
+    fn parse_splits_the_request_line_and_reads_headers() {
+        let request = Request::parse(b"GET /echo/hi?x=1 HTTP/1.1\r\nHost: localhost\r\nUser-Agent: curl\r\n\r\n").unwrap();
+        assert_eq!(request.method, HttpMethod::Get);
+        assert_eq!(request.target, "/echo/hi?x=1");
+        assert_eq!(request.path, "/echo/hi");
+        assert_eq!(request.query["x"], "1");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.headers["host"], "localhost");
+        assert_eq!(request.headers["user-agent"], "curl");
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn parse_splits_off_and_decodes_the_query() {
+        let request = Request::parse(b"GET /echo/foo?x=1&name=a%20b&flag HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/echo/foo");
+        assert_eq!(request.query["x"], "1");
+        assert_eq!(request.query["name"], "a b");
+        assert_eq!(request.query["flag"], "");
+    }
+
+    #[test]
+    fn only_query_and_form_decoding_turns_plus_into_space() {
+        assert_eq!(percent_decode("a+b%2Bc%zz"), "a b+c%zz");
+        assert_eq!(path_decode("a+b%2Bc%20d"), "a+b+c d");
+        assert!(matches!(path_decode("a+b"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn parse_rejects_a_short_request_line() {
+        for raw in [&b"GET /\r\nHost: x\r\n\r\n"[..], b"GET\r\n\r\n", b"\r\n\r\n"] {
+            assert!(matches!(Request::parse(raw), Err(ParseError::MalformedRequestLine(_))));
+        }
+    }
+
+    #[test]
+    fn parse_rejects_control_and_non_ascii_bytes_anywhere_in_the_target() {
+        for target in [&b"/echo/x?a\x01b"[..], b"/echo/x?a\nInjected:1", b"/echo/\x7f", b"/echo/caf\xc3\xa9"] {
+            let raw = [b"GET ", target, b" HTTP/1.1\r\nHost: x\r\n\r\n"].concat();
+            assert!(matches!(Request::parse(&raw), Err(ParseError::InvalidTarget(_))), "{:?}", target);
+        }
+        assert!(Request::parse(b"GET /echo/x?a=%01 HTTP/1.1\r\nHost: x\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn parse_headers_ignores_lines_without_a_colon() {
+        let headers = parse_headers(["Host: x", "no colon here", "Accept: */*"].into_iter()).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers["accept"], "*/*");
+    }
+
+    #[test]
+    fn parse_headers_trims_values_and_lowercases_names() {
+        let headers = parse_headers(["X-Thing :   spaced out \t", "Content-TYPE:text/plain"].into_iter()).unwrap();
+        assert_eq!(headers["x-thing"], "spaced out");
+        assert_eq!(headers["content-type"], "text/plain");
+    }
+
+    #[test]
+    fn parse_headers_joins_repeats_and_unfolds_continuations() {
+        let headers = parse_headers(["Accept: a", "Accept: b", "X-Long: one", "  two"].into_iter()).unwrap();
+        assert_eq!(headers["accept"], "a, b");
+        assert_eq!(headers["x-long"], "one two");
+    }
+
+    #[test]
+    fn parse_headers_refuses_control_characters_but_not_tabs() {
+        for line in ["X-Bad: a\rb", "X-Bad: a\nInjected: 1", "X-Bad: a\x00b", "X-Bad: a\x7f", " \x01continued"] {
+            assert!(matches!(parse_headers(["X-Ok: 1", line].into_iter()), Err(ParseError::InvalidHeader(_))), "{:?}", line);
+        }
+        assert_eq!(parse_headers(["X-Tabbed: a\tb"].into_iter()).unwrap()["x-tabbed"], "a\tb");
+    }
+
+    fn read(input: &[u8]) -> Result<Request, ReadError> {
+        let mut pending = Vec::new();
+        let length = read_request(&mut Wire::new(input), &mut pending, &config(), &mut false)?;
+        Ok(Request::parse(&pending[..length]).unwrap())
+    }
+
+    #[test]
+    fn expect_continue_is_answered_once_the_headers_are_in() {
+        let head = b"PUT /files/x HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\n";
+        // what a client waiting for 100 Continue has sent by the first read
+        let mut pending = head.to_vec();
+        let mut wire = Wire::new(b"abc");
+        let mut continue_sent = false;
+        let length = read_request(&mut wire, &mut pending, &config(), &mut continue_sent).unwrap();
+        assert_eq!(wire.output, b"HTTP/1.1 100 Continue\r\n\r\n");
+        assert!(continue_sent);
+        assert_eq!(&pending[length - 3..length], b"abc");
+    }
+
+    #[test]
+    fn expect_continue_goes_out_once_across_retries() {
+        let head = b"PUT /files/x HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\n";
+        let mut pending = head.to_vec();
+        let mut continue_sent = true;
+        let mut wire = Wire::new(b"abc");
+        read_request(&mut wire, &mut pending, &config(), &mut continue_sent).unwrap();
+        assert!(wire.output.is_empty());
+    }
+
+    #[test]
+    fn expect_continue_is_not_sent_when_the_body_is_already_here() {
+        let input = b"PUT /files/x HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\nabc";
+        let mut pending = input.to_vec();
+        let mut wire = Wire::new(b"");
+        read_request(&mut wire, &mut pending, &config(), &mut false).unwrap();
+        assert!(wire.output.is_empty());
+    }
+
+    #[test]
+    fn malformed_content_lengths_are_refused() {
+        for length in ["abc", "-1", "+3", "", "3 4"] {
+            let input = format!("POST /files/bad HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\nhello", length);
+            assert!(matches!(read(input.as_bytes()), Err(ReadError::InvalidFraming(_))), "{:?}", length);
+        }
+    }
+
+    #[test]
+    fn a_missing_content_length_means_no_body() {
+        let mut pending = Vec::new();
+        let input = b"POST / HTTP/1.1\r\nHost: x\r\n\r\nGET / HTTP/1.1\r\n";
+        let length = read_request(&mut Wire::new(input), &mut pending, &config(), &mut false).unwrap();
+        assert_eq!(&pending[length..], b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn repeated_identical_content_lengths_keep_the_body() {
+        let request = read(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc").unwrap();
+        assert_eq!(request.body, b"abc");
+    }
+
+    #[test]
+    fn conflicting_content_lengths_are_refused() {
+        let input = b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\nContent-Length: 40\r\n\r\n{}GET /echo/smuggled HTTP/1.1\r\n";
+        assert!(matches!(read(input), Err(ReadError::InvalidFraming(_))));
+        let joined = b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 2, 40\r\n\r\n{}";
+        assert!(matches!(read(joined), Err(ReadError::InvalidFraming(_))));
+    }
+
+    #[test]
+    fn scan_chunked_decodes_chunks_and_trailers() {
+        let body = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nTrailer: x\r\n\r\nnext";
+        let ChunkScan { chunks, pos: end, .. } = scan_all(body, 100).unwrap().unwrap();
+        let data: Vec<u8> = chunks.into_iter().flat_map(|chunk| body[chunk].to_vec()).collect();
+        assert_eq!(data, b"Wikipedia");
+        assert_eq!(&body[end..], b"next");
+    }
+
+    #[test]
+    fn scan_chunked_waits_for_more() {
+        assert!(scan_all(b"4\r\nWi", 100).unwrap().is_none());
+        assert!(scan_all(b"4\r\nWiki\r\n0\r\n", 100).unwrap().is_none());
+    }
+
+    #[test]
+    fn scan_chunked_rejects_bad_framing() {
+        assert!(matches!(scan_all(b"zz\r\n", 100), Err(ReadError::InvalidFraming(_))));
+        assert!(matches!(scan_all(b"2\r\nabc\r\n", 100), Err(ReadError::InvalidFraming(_))));
+    }
+
+    #[test]
+    fn scan_chunked_refuses_sizes_over_the_limit_without_overflowing() {
+        assert!(matches!(scan_all(b"65\r\n", 100), Err(ReadError::BodyTooLarge(101))));
+        let huge = b"1\r\na\r\nffffffffffffffff\r\n";
+        assert!(matches!(scan_all(huge, 100), Err(ReadError::BodyTooLarge(usize::MAX))));
+        assert!(matches!(scan_all(huge, usize::MAX), Err(ReadError::BodyTooLarge(usize::MAX))));
+        // within the limit, but past the end of any buffer
+        let past_the_end = b"1\r\na\r\nfffffffffffffffe\r\n";
+        assert!(matches!(scan_all(past_the_end, usize::MAX), Err(ReadError::InvalidFraming(_))));
+    }
+
+    #[test]
+    fn scan_chunked_resumes_where_the_last_read_left_off() {
+        let body = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nTrailer: x\r\n\r\nnext";
+        let mut scan = ChunkScan::default();
+        // a byte at a time, the way a slow client might send it
+        let end = (1..=body.len())
+            .find_map(|len| scan_chunked(&body[..len], 100, &mut scan).unwrap())
+            .unwrap();
+        assert_eq!(&body[end..], b"next");
+        assert_eq!(scan.chunks, [3..7, 18..23]);
+        assert_eq!(scan.total, 9);
+        // nothing before the end of the body is looked at again
+        assert_eq!((scan.pos, scan.searched), (end, end));
+    }
+}
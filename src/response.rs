@@ -0,0 +1,367 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    time::SystemTime,
+};
+
+use crate::{date::http_date, json, negotiate};
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    // streamed from disk when written, `u64` bytes of it
+    File(File, u64),
+    // read when written, like a file but from anywhere, `u64` bytes of it
+    Reader(Box<dyn Read + Send>, u64),
+    // read to the end when written, sent with chunked transfer coding since
+    // its length isn't known up front
+    Stream(Box<dyn Read + Send>),
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Body::File(_, length) => f.debug_tuple("File").field(length).finish(),
+            Body::Reader(_, length) => f.debug_tuple("Reader").field(length).finish(),
+            Body::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+impl Body {
+    // The length that goes in Content-Length; a stream has none and counts as 0.
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(_, length) | Body::Reader(_, length) => *length,
+            Body::Stream(_) => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: u16, reason: &str) -> Response {
+        Response {
+            status,
+            reason: reason.to_string(),
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    pub fn ok() -> Response {
+        Response::new(200, "OK")
+    }
+
+    pub fn created() -> Response {
+        Response::new(201, "Created")
+    }
+
+    pub fn partial_content() -> Response {
+        Response::new(206, "Partial Content")
+    }
+
+    pub fn no_content() -> Response {
+        Response::new(204, "No Content")
+    }
+
+    pub fn moved_permanently(location: &str) -> Response {
+        Response::new(301, "Moved Permanently").header("Location", location)
+    }
+
+    pub fn not_modified() -> Response {
+        Response::new(304, "Not Modified")
+    }
+
+    pub fn bad_request() -> Response {
+        Response::new(400, "Bad Request")
+    }
+
+    pub fn unauthorized() -> Response {
+        Response::new(401, "Unauthorized")
+    }
+
+    pub fn forbidden() -> Response {
+        Response::new(403, "Forbidden")
+    }
+
+    pub fn not_found() -> Response {
+        Response::new(404, "Not Found")
+    }
+
+    pub fn method_not_allowed() -> Response {
+        Response::new(405, "Method Not Allowed")
+    }
+
+    pub fn request_timeout() -> Response {
+        Response::new(408, "Request Timeout")
+    }
+
+    pub fn payload_too_large() -> Response {
+        Response::new(413, "Payload Too Large")
+    }
+
+    pub fn uri_too_long() -> Response {
+        Response::new(414, "URI Too Long")
+    }
+
+    pub fn unsupported_media_type() -> Response {
+        Response::new(415, "Unsupported Media Type")
+    }
+
+    pub fn too_many_requests() -> Response {
+        Response::new(429, "Too Many Requests")
+    }
+
+    pub fn request_header_fields_too_large() -> Response {
+        Response::new(431, "Request Header Fields Too Large")
+    }
+
+    pub fn range_not_satisfiable() -> Response {
+        Response::new(416, "Range Not Satisfiable")
+    }
+
+    pub fn internal_server_error() -> Response {
+        Response::new(500, "Internal Server Error")
+    }
+
+    pub fn not_implemented() -> Response {
+        Response::new(501, "Not Implemented")
+    }
+
+    pub fn service_unavailable() -> Response {
+        Response::new(503, "Service Unavailable")
+    }
+
+    pub fn http_version_not_supported() -> Response {
+        Response::new(505, "HTTP Version Not Supported")
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // Notes that the response depends on request header `name`, so caches keep
+    // a copy per value of it. Every name ends up in one Vary header, listed once.
+    pub fn vary(mut self, name: &str) -> Response {
+        let existing = self.headers.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case("Vary"));
+        match existing {
+            Some((_, value)) => {
+                let listed = value
+                    .split(',')
+                    .map(str::trim)
+                    .any(|listed| listed == "*" || listed.eq_ignore_ascii_case(name));
+                if !listed {
+                    value.push_str(", ");
+                    value.push_str(name);
+                }
+                self
+            }
+            None => self.header("Vary", name),
+        }
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    pub fn file(mut self, file: File, length: u64) -> Response {
+        self.body = Body::File(file, length);
+        self
+    }
+
+    // A body of known length that isn't a plain file.
+    pub fn reader(mut self, reader: impl Read + Send + 'static, length: u64) -> Response {
+        self.body = Body::Reader(Box::new(reader), length);
+        self
+    }
+
+    // A body of unknown length, sent chunked. Only HTTP/1.1 clients can read it.
+    pub fn stream(mut self, reader: impl Read + Send + 'static) -> Response {
+        self.body = Body::Stream(Box::new(reader));
+        self
+    }
+
+    // Drops the body but keeps the Content-Length or chunked framing it would
+    // have had, which is what a HEAD response needs.
+    pub fn without_body(mut self) -> Response {
+        if matches!(self.body, Body::Stream(_)) {
+            self = self.header("Transfer-Encoding", "chunked");
+        } else if !self.bodiless() && !self.has_header("Content-Length") {
+            let length = self.body.len().to_string();
+            self = self.header("Content-Length", &length);
+        }
+        self.body = Body::Bytes(Vec::new());
+        self
+    }
+
+    // 1xx, 204 and 304 responses never have a body, so they carry no length
+    // either: RFC 9110 forbids one on the first two, and on a 304 it would be
+    // the length of the representation the client already has.
+    fn bodiless(&self) -> bool {
+        matches!(self.status, 100..=199 | 204 | 304)
+    }
+
+    // Gives an error that has no body of its own its status line as one, so a
+    // browser shows more than a blank page. `accept` is the client's Accept
+    // header: JSON and HTML clients get a body in their format, and everyone
+    // else plain text, so the response varies on it.
+    pub fn with_default_body(self, accept: Option<&str>) -> Response {
+        if self.status < 400 || !matches!(&self.body, Body::Bytes(body) if body.is_empty()) {
+            return self;
+        }
+        let status_line = format!("{} {}", self.status, self.reason);
+        let response = self.vary("Accept");
+        match negotiate::media_type(accept, &["text/plain", "application/json", "text/html"]) {
+            Some("application/json") => {
+                let body = format!("{{\"error\":{}}}\n", json::string(&response.reason.to_ascii_lowercase()));
+                response.header("Content-Type", "application/json").body(body)
+            }
+            Some("text/html") => {
+                let body = format!(
+                    "<!DOCTYPE html>\n<html><head><title>{0}</title></head><body><h1>{0}</h1></body></html>\n",
+                    status_line
+                );
+                response.header("Content-Type", "text/html; charset=utf-8").body(body)
+            }
+            _ => response.header("Content-Type", "text/plain").body(format!("{}\n", status_line)),
+        }
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+
+    // The first value given for header `name`, if any.
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Content-Length (or chunked framing for a stream) is filled in from the
+    // body here, so handlers can't get it wrong, and every response gets
+    // stamped with the current Date. An empty body still gets
+    // `Content-Length: 0`, so a keep-alive client needn't wait to see if more
+    // is coming.
+    pub fn head(&self) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        if !self.has_header("Date") {
+            head.push_str(&format!("Date: {}\r\n", http_date(SystemTime::now())));
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if matches!(self.body, Body::Stream(_)) {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+        } else if !self.bodiless() && !self.has_header("Content-Length") && !self.has_header("Transfer-Encoding") {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    pub fn write_to<W: Write>(self, out: &mut W) -> io::Result<()> {
+        let mut bytes = self.head().into_bytes();
+        match self.body {
+            Body::Bytes(body) => {
+                bytes.extend_from_slice(&body);
+                out.write_all(&bytes)
+            }
+            Body::File(file, length) => {
+                out.write_all(&bytes)?;
+                let copied = io::copy(&mut file.take(length), out)?;
+                if copied < length {
+                    // the file shrank under us; the client is owed bytes we can't send
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated while sending"));
+                }
+                Ok(())
+            }
+            Body::Reader(reader, length) => {
+                out.write_all(&bytes)?;
+                let copied = io::copy(&mut reader.take(length), out)?;
+                if copied < length {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body ended early while sending"));
+                }
+                Ok(())
+            }
+            Body::Stream(mut reader) => {
+                out.write_all(&bytes)?;
+                let mut buf = [0; 8192];
+                loop {
+                    let n = match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    out.write_all(format!("{:x}\r\n", n).as_bytes())?;
+                    out.write_all(&buf[..n])?;
+                    out.write_all(b"\r\n")?;
+                    // a slow stream, like an event feed, should arrive as it's produced
+                    out.flush()?;
+                }
+                out.write_all(b"0\r\n\r\n")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::testing::{dechunk, exchange, replies, request, temp_dir};
+
+    #[test]
+    fn a_stream_is_written_chunked_and_decodes_back() {
+        // big enough to take several chunks
+        let body: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let mut out = Vec::new();
+        Response::ok().stream(Cursor::new(body.clone())).write_to(&mut out).unwrap();
+        let split = out.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&out[..split]);
+        assert!(head.contains("\r\nTransfer-Encoding: chunked\r\n"), "{}", head);
+        assert!(!head.contains("Content-Length"));
+        let (decoded, rest) = dechunk(&out[split..]);
+        assert_eq!(decoded, body);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn vary_lists_each_name_once() {
+        let response = Response::ok().vary("Accept-Encoding").vary("Accept").vary("accept-encoding");
+        assert_eq!(response.header_value("Vary"), Some("Accept-Encoding, Accept"));
+        assert_eq!(response.headers.iter().filter(|(name, _)| name == "Vary").count(), 1);
+        let response = Response::ok().header("Vary", "*").vary("Accept");
+        assert_eq!(response.header_value("Vary"), Some("*"));
+    }
+
+    #[test]
+    fn a_gzipped_response_varies_on_accept_encoding() {
+        let dir = temp_dir("vary");
+        std::fs::write(dir.join("big.txt"), "text ".repeat(1000)).unwrap();
+        let args = ["--directory", dir.to_str().unwrap()];
+        let headers = [("Accept-Encoding", "gzip")];
+        let reply = replies(&exchange(&args, &request("GET", "/files/big.txt", &headers, b""))).remove(0);
+        assert_eq!(reply.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(reply.header("Vary"), Some("Accept-Encoding"));
+    }
+}
@@ -0,0 +1,159 @@
+use std::{collections::HashMap, io};
+
+use crate::{
+    request::{HttpMethod, Request},
+    response::Response,
+    server::Server,
+};
+
+// Named parts of the path captured by a route pattern.
+pub type Params = HashMap<String, String>;
+
+pub type Handler = Box<dyn Fn(&Request, &Params, &Server) -> io::Result<Response> + Send + Sync>;
+
+enum Segment {
+    Literal(String),
+    // `:name`, exactly one non-empty segment
+    Param(String),
+    // `*name`, whatever is left of the path, slashes included; may be empty
+    Rest(String),
+}
+
+struct Route {
+    // as registered, for listing
+    pattern: String,
+    methods: Vec<HttpMethod>,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+pub enum Match<'a> {
+    Found(&'a Handler, Params),
+    // the path is known, but only for these methods
+    MethodNotAllowed(Vec<HttpMethod>),
+    NotFound,
+}
+
+// Routes are tried in the order they were added, so earlier ones win.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    // `pattern` is a path like `/echo/:message` or `/files/*path`. A GET
+    // route answers HEAD too, with the body dropped on the way out.
+    pub fn route<F>(mut self, methods: &[HttpMethod], pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params, &Server) -> io::Result<Response> + Send + Sync + 'static,
+    {
+        let segments = pattern
+            .trim_start_matches('/')
+            .split('/')
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Rest(name.to_string())
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect();
+        let mut methods = methods.to_vec();
+        if methods.contains(&HttpMethod::Get) && !methods.contains(&HttpMethod::Head) {
+            methods.push(HttpMethod::Head);
+        }
+        self.routes.push(Route {
+            pattern: pattern.to_string(),
+            methods,
+            segments,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn find(&self, method: HttpMethod, path: &str) -> Match<'_> {
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let Some(params) = match_path(&route.segments, path) else {
+                continue;
+            };
+            if route.methods.contains(&method) {
+                return Match::Found(&route.handler, params);
+            }
+            add_methods(&mut allowed, &route.methods);
+        }
+        if allowed.is_empty() {
+            Match::NotFound
+        } else {
+            Match::MethodNotAllowed(allowed)
+        }
+    }
+
+    // Each route's pattern and methods, in the order they're tried.
+    pub fn routes(&self) -> impl Iterator<Item = (&str, &[HttpMethod])> {
+        self.routes.iter().map(|route| (route.pattern.as_str(), route.methods.as_slice()))
+    }
+
+    // Every method any route answers to.
+    pub fn methods(&self) -> Vec<HttpMethod> {
+        let mut methods = Vec::new();
+        for route in &self.routes {
+            add_methods(&mut methods, &route.methods);
+        }
+        methods
+    }
+
+    // Every method some route on `path` answers to.
+    pub fn allowed_methods(&self, path: &str) -> Vec<HttpMethod> {
+        let mut allowed: Vec<HttpMethod> = Vec::new();
+        for route in self.routes.iter().filter(|route| match_path(&route.segments, path).is_some()) {
+            add_methods(&mut allowed, &route.methods);
+        }
+        allowed
+    }
+}
+
+fn add_methods(allowed: &mut Vec<HttpMethod>, methods: &[HttpMethod]) {
+    for method in methods {
+        if !allowed.contains(method) {
+            allowed.push(*method);
+        }
+    }
+}
+
+fn match_path(segments: &[Segment], path: &str) -> Option<Params> {
+    let mut params = Params::new();
+    let mut rest = path.strip_prefix('/')?;
+    for (i, segment) in segments.iter().enumerate() {
+        if let Segment::Rest(name) = segment {
+            params.insert(name.clone(), rest.to_string());
+            return Some(params);
+        }
+
+        let (head, tail) = match rest.split_once('/') {
+            Some((head, tail)) => (head, Some(tail)),
+            None => (rest, None),
+        };
+        match segment {
+            Segment::Literal(literal) if head == literal => {}
+            Segment::Param(name) if !head.is_empty() => {
+                params.insert(name.clone(), head.to_string());
+            }
+            _ => return None,
+        }
+
+        let last = i + 1 == segments.len();
+        match tail {
+            None if last => return Some(params),
+            Some(tail) if !last => rest = tail,
+            _ => return None,
+        }
+    }
+    None
+}
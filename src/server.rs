@@ -0,0 +1,84 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rustls::ServerConfig;
+use thiserror::Error;
+
+use crate::{
+    config::Config,
+    log::AccessLog,
+    metrics::Metrics,
+    middleware::Middleware,
+    router::Router,
+    tls::{self, TlsError},
+};
+
+#[derive(Debug, Error)]
+pub enum StartupError {
+    #[error("failed to open access log: {0}")]
+    AccessLog(io::Error),
+    #[error("failed to set up TLS: {0}")]
+    Tls(#[from] TlsError),
+}
+
+// State shared between the accept loop and every connection on the pool.
+pub struct Server {
+    pub config: Config,
+    // set by the signal handler; the accept loop, keep-alive loops and event
+    // streams watch it
+    pub shutdown: Arc<AtomicBool>,
+    // connections accepted but not yet finished, queued ones included
+    pub active_connections: AtomicUsize,
+    pub access_log: AccessLog,
+    pub metrics: Metrics,
+    // set when serving HTTPS
+    pub tls: Option<Arc<ServerConfig>>,
+    pub router: Router,
+    // run around every routed request, outermost first
+    pub middleware: Vec<Middleware>,
+}
+
+impl Server {
+    // Fails if the access log file can't be opened or the TLS certificate loaded.
+    pub fn new(config: Config, router: Router, middleware: Vec<Middleware>) -> Result<Server, StartupError> {
+        let access_log = AccessLog::open(config.access_log.as_deref(), config.log_format).map_err(StartupError::AccessLog)?;
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::load_config(cert, key)?),
+            _ => None,
+        };
+        Ok(Server {
+            config,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            active_connections: AtomicUsize::new(0),
+            access_log,
+            metrics: Metrics::default(),
+            tls,
+            router,
+            middleware,
+        })
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    // Waits for open connections to finish, giving up after `timeout` if one
+    // is given, and says how many were still open.
+    pub fn wait_for_connections(&self, timeout: Option<Duration>) -> usize {
+        let started = Instant::now();
+        loop {
+            let active = self.active_connections.load(Ordering::SeqCst);
+            if active == 0 || timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+                return active;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::date::http_date;
+
+// How often a waiting event stream checks whether the server is shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// One Server-Sent Events message; each line of `data` gets its own `data:` field.
+pub fn event(data: &str) -> String {
+    let mut event = String::new();
+    for line in data.lines() {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    event.push('\n');
+    event
+}
+
+// An endless `text/event-stream` body carrying the current time every
+// `interval`, for use as a streamed response. It ends when the server starts
+// shutting down; a client that goes away shows up as a failed write instead.
+pub struct Clock {
+    interval: Duration,
+    next: Instant,
+    shutdown: Arc<AtomicBool>,
+    // the part of the latest event the last read had no room for
+    pending: Vec<u8>,
+}
+
+impl Clock {
+    pub fn new(interval: Duration, shutdown: Arc<AtomicBool>) -> Clock {
+        Clock {
+            interval,
+            next: Instant::now(),
+            shutdown,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Read for Clock {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            if self.shutdown.load(Ordering::SeqCst) {
+                return Ok(0);
+            }
+            let now = Instant::now();
+            if now >= self.next {
+                self.next += self.interval;
+                self.pending = event(&http_date(SystemTime::now())).into_bytes();
+            } else {
+                thread::sleep((self.next - now).min(SHUTDOWN_POLL_INTERVAL));
+            }
+        }
+        let n = self.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
@@ -0,0 +1,166 @@
+// Helpers shared by the unit tests.
+use std::{
+    fs,
+    io::{self, Cursor, Read, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{config::Config, handlers, middleware, net::ReadTimeout, server::Server};
+
+// A connection whose client sends `input` and then hangs up. Whatever the
+// server writes collects in `output`.
+pub struct Wire {
+    input: Cursor<Vec<u8>>,
+    pub output: Vec<u8>,
+}
+
+impl Wire {
+    pub fn new(input: &[u8]) -> Wire {
+        Wire { input: Cursor::new(input.to_vec()), output: Vec::new() }
+    }
+}
+
+impl Read for Wire {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Wire {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Input is all there up front, so reads never wait.
+impl ReadTimeout for Wire {
+    fn set_read_timeout(&self, _: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// What the server runs with given `args`, on top of the defaults.
+pub fn config(args: &[&str]) -> Config {
+    Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap()
+}
+
+// A raw HTTP/1.1 request with a Host header, `headers`, and `body` after a
+// matching Content-Length when it isn't empty.
+pub fn request(method: &str, target: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, target);
+    for (name, value) in headers {
+        raw.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !body.is_empty() {
+        raw.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    raw.push_str("\r\n");
+    let mut raw = raw.into_bytes();
+    raw.extend_from_slice(body);
+    raw
+}
+
+// A server started with `args`, with the real routes and middleware. Its
+// access log is thrown away so it doesn't clutter test output.
+pub fn server(args: &[&str]) -> Server {
+    let mut args = args.to_vec();
+    args.extend(["--quiet", "--access-log", "/dev/null"]);
+    let config = config(&args);
+    let router = handlers::routes(&config);
+    let middleware = middleware::stack(&config);
+    Server::new(config, router, middleware).unwrap()
+}
+
+// Sends `input` down one connection to `server` and returns everything it
+// wrote back before the connection ended.
+pub fn exchange_with(server: &Server, input: &[u8]) -> Vec<u8> {
+    let mut wire = Wire::new(input);
+    let _ = crate::handle_client(&mut wire, "127.0.0.1", server, crate::handle_request);
+    wire.output
+}
+
+// `exchange_with` for a server that's only needed for the one connection.
+pub fn exchange(args: &[&str], input: &[u8]) -> Vec<u8> {
+    exchange_with(&server(args), input)
+}
+
+// One response as the client saw it, with a chunked body already decoded.
+#[derive(Debug)]
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Splits what a server wrote into its responses, framing each body by
+// Content-Length or chunked coding. Interim 100 Continue responses are
+// skipped. Not for HEAD answers, whose Content-Length has no body behind it.
+pub fn replies(mut output: &[u8]) -> Vec<Reply> {
+    let mut replies = Vec::new();
+    while !output.is_empty() {
+        let head_end = output.windows(4).position(|w| w == b"\r\n\r\n").expect("response head") + 4;
+        let head = String::from_utf8_lossy(&output[..head_end - 4]).into_owned();
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+            .collect();
+        output = &output[head_end..];
+        if status == 100 {
+            continue;
+        }
+        let mut reply = Reply { status, headers, body: Vec::new() };
+        if reply.header("Transfer-Encoding") == Some("chunked") {
+            let (body, rest) = dechunk(output);
+            reply.body = body;
+            output = rest;
+        } else {
+            let length: usize = reply.header("Content-Length").map_or(0, |length| length.parse().unwrap());
+            reply.body = output[..length].to_vec();
+            output = &output[length..];
+        }
+        replies.push(reply);
+    }
+    replies
+}
+
+// Decodes a chunked body, returning it and whatever follows it.
+pub fn dechunk(mut input: &[u8]) -> (Vec<u8>, &[u8]) {
+    let mut body = Vec::new();
+    loop {
+        let line_end = input.windows(2).position(|w| w == b"\r\n").expect("chunk size line");
+        let size = usize::from_str_radix(std::str::from_utf8(&input[..line_end]).unwrap(), 16).unwrap();
+        input = &input[line_end + 2..];
+        if size == 0 {
+            assert!(input.starts_with(b"\r\n"), "chunked body not terminated");
+            return (body, &input[2..]);
+        }
+        body.extend_from_slice(&input[..size]);
+        assert_eq!(&input[size..size + 2], b"\r\n");
+        input = &input[size + 2..];
+    }
+}
+
+// A fresh, empty directory for one test to serve files from.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("http-server-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
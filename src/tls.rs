@@ -0,0 +1,45 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("failed to read {}: {source}", path.display())]
+    Pem {
+        path: PathBuf,
+        source: rustls::pki_types::pem::Error,
+    },
+    #[error("no certificates in {}", .0.display())]
+    NoCertificates(PathBuf),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+}
+
+// Builds the server side TLS settings from a PEM certificate chain and key.
+pub fn load_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, TlsError> {
+    let pem_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| TlsError::Pem { path, source }
+    };
+    let certs = CertificateDer::pem_file_iter(cert)
+        .map_err(pem_error(cert))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(pem_error(cert))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(cert.to_path_buf()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key).map_err(pem_error(key))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,217 @@
+use std::io::{self, Read, Write};
+
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+
+use crate::{
+    base64,
+    request::{HttpMethod, Request},
+    response::Response,
+    server::Server,
+};
+
+// Appended to the client's key before hashing, per RFC 6455.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// close status codes
+const PROTOCOL_ERROR: u16 = 1002;
+const MESSAGE_TOO_BIG: u16 = 1009;
+const GOING_AWAY: u16 = 1001;
+
+// Checks an upgrade request and answers it with 101, or with the error that
+// explains what's wrong. What happens after a 101 is up to `echo`.
+pub fn handshake(request: &Request) -> Response {
+    let has_token = |name: &str, token: &str| {
+        request
+            .headers
+            .get(name)
+            .is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    if !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+        return Response::new(426, "Upgrade Required").header("Upgrade", "websocket");
+    }
+    // a HEAD would get the 101 but no connection to go with it
+    if request.method != HttpMethod::Get {
+        return Response::method_not_allowed().header("Allow", "GET");
+    }
+    if request.headers.get("sec-websocket-version").map(|v| v.trim()) != Some("13") {
+        return Response::bad_request().header("Sec-WebSocket-Version", "13");
+    }
+    // the key is 16 random bytes, base64-encoded
+    let Some(key) = request
+        .headers
+        .get("sec-websocket-key")
+        .map(|key| key.trim())
+        .filter(|key| base64::decode(key).is_some_and(|bytes| bytes.len() == 16))
+    else {
+        return Response::bad_request()
+            .header("Content-Type", "text/plain")
+            .body("missing or invalid Sec-WebSocket-Key\n");
+    };
+
+    Response::new(101, "Switching Protocols")
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", &accept_key(key))
+}
+
+fn accept_key(key: &str) -> String {
+    let hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, format!("{}{}", key, HANDSHAKE_GUID).as_bytes());
+    base64::encode(hash.as_ref())
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Sends every data frame back as it came, answers pings, and closes when the
+// client does. `pending` holds whatever the client sent right after the
+// handshake. Read timeouts just mean a quiet client, so they only end the
+// connection once the server is shutting down.
+pub fn echo<S: Read + Write>(stream: &mut S, mut pending: Vec<u8>, server: &Server) -> io::Result<()> {
+    let max_payload = server.config.max_body_size;
+    let mut buf = [0; 8192];
+    loop {
+        match parse_frame(&pending, max_payload) {
+            Ok(Some((frame, used))) => {
+                pending.drain(..used);
+                match frame.opcode {
+                    TEXT | BINARY | CONTINUATION => write_frame(stream, frame.fin, frame.opcode, &frame.payload)?,
+                    PING => write_frame(stream, true, PONG, &frame.payload)?,
+                    PONG => {}
+                    // echo the status code back, then we're done
+                    CLOSE => return write_frame(stream, true, CLOSE, &frame.payload[..frame.payload.len().min(2)]),
+                    _ => return close(stream, PROTOCOL_ERROR),
+                }
+                continue;
+            }
+            Ok(None) => {}
+            Err(status) => return close(stream, status),
+        }
+
+        match stream.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => pending.extend_from_slice(&buf[..n]),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if server.is_shutting_down() {
+                    return close(stream, GOING_AWAY);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// The first whole frame in `bytes` and how many bytes it took, None until one
+// has arrived, or the close status to send for a frame we won't take.
+fn parse_frame(bytes: &[u8], max_payload: usize) -> Result<Option<(Frame, usize)>, u16> {
+    let [first, second, ..] = *bytes else {
+        return Ok(None);
+    };
+    // clients must mask what they send
+    if second & 0x80 == 0 {
+        return Err(PROTOCOL_ERROR);
+    }
+    let (length, mut pos) = match second & 0x7f {
+        126 if bytes.len() >= 4 => (u16::from_be_bytes([bytes[2], bytes[3]]) as u64, 4),
+        127 if bytes.len() >= 10 => (u64::from_be_bytes(bytes[2..10].try_into().unwrap_or_default()), 10),
+        126 | 127 => return Ok(None),
+        length => (length as u64, 2),
+    };
+    if length > max_payload as u64 {
+        return Err(MESSAGE_TOO_BIG);
+    }
+    let length = length as usize;
+    if bytes.len() < pos + 4 + length {
+        return Ok(None);
+    }
+    let mask = &bytes[pos..pos + 4];
+    pos += 4;
+    let payload = bytes[pos..pos + length]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+    Ok(Some((
+        Frame {
+            fin: first & 0x80 != 0,
+            opcode: first & 0x0f,
+            payload,
+        },
+        pos + length,
+    )))
+}
+
+// Server frames go out unmasked.
+fn write_frame<W: Write>(out: &mut W, fin: bool, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    out.write_all(&frame)?;
+    out.flush()
+}
+
+fn close<W: Write>(out: &mut W, status: u16) -> io::Result<()> {
+    write_frame(out, true, CLOSE, &status.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{exchange, request};
+
+    // the handshake and masked "Hello" frame from RFC 6455's examples
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+    const MASKED_HELLO: &[u8] = b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58";
+    const MASKED_CLOSE: &[u8] = b"\x88\x80\x01\x02\x03\x04";
+
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        assert_eq!(accept_key(KEY), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn a_masked_text_frame_comes_back_unmasked() {
+        let headers = [
+            ("Upgrade", "websocket"),
+            ("Connection", "Upgrade"),
+            ("Sec-WebSocket-Version", "13"),
+            ("Sec-WebSocket-Key", KEY),
+        ];
+        let mut input = request("GET", "/ws", &headers, b"");
+        input.extend_from_slice(MASKED_HELLO);
+        input.extend_from_slice(MASKED_CLOSE);
+        let output = exchange(&[], &input);
+        let split = output.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&output[..split]);
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+        assert!(head.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert_eq!(&output[split..], b"\x81\x05Hello\x88\x00");
+    }
+
+    #[test]
+    fn unmasked_client_frames_are_refused() {
+        assert!(matches!(parse_frame(b"\x81\x05Hello", 1024), Err(PROTOCOL_ERROR)));
+        assert!(matches!(parse_frame(&MASKED_HELLO[..6], 1024), Ok(None)));
+        assert!(matches!(parse_frame(MASKED_HELLO, 4), Err(MESSAGE_TOO_BIG)));
+    }
+}